@@ -0,0 +1,100 @@
+//! Periodic snapshots of the pixels received so far, written next to
+//! the output file while the frame renders.
+use crate::{file_format::FileFormat, hdr, image_info, pfm, write_to_file, ImageData};
+use exr::{
+    image::write_options,
+    prelude::rgba_image::{Compression, Encoding, LineOrder, Pixel},
+};
+use std::thread::JoinHandle;
+
+/// Starts writing a checkpoint of `image` on its own thread. The
+/// renderer only waits for the pixels to be copied.
+///
+/// The color is unpremultiplied like that of the final image. EXRs are
+/// written as RLE compressed scan lines without levels, whatever the
+/// final image uses; both are quick to write.
+pub fn start(image: &ImageData) -> Option<JoinHandle<()>> {
+    let rgb_index = image.rgb_index?;
+    let file_name = image.checkpoint_file_name();
+    let (width, height) = (image.width, image.height);
+    let unpremultiply = !image.premultiply;
+
+    eprintln!("[r-display] writing checkpoint {} ...", file_name);
+
+    match image.format {
+        FileFormat::Exr => {
+            let alpha_index = image.alpha_index?;
+            let rgba = image
+                .data
+                .chunks(image.num_channels)
+                .flat_map(|pixel| {
+                    let alpha = pixel[alpha_index];
+                    // Ignore pixels whose alpha is zero.
+                    let scale = if unpremultiply && 0.0 != alpha {
+                        1. / alpha
+                    } else {
+                        1.
+                    };
+                    [
+                        pixel[rgb_index] * scale,
+                        pixel[rgb_index + 1] * scale,
+                        pixel[rgb_index + 2] * scale,
+                        alpha,
+                    ]
+                })
+                .collect::<Vec<_>>();
+            let image_info = image_info(image).with_encoding(Encoding {
+                compression: Compression::RLE,
+                tile_size: None,
+                line_order: LineOrder::Increasing,
+            });
+
+            Some(std::thread::spawn(move || {
+                write_to_file(&file_name, |file_name| {
+                    image_info.write_pixels_to_file(
+                        file_name,
+                        write_options::low(),
+                        |position: exr::math::Vec2<usize>| {
+                            let index = 4 * (position.x() + position.y() * width);
+                            Pixel::rgba(
+                                rgba[index],
+                                rgba[index + 1],
+                                rgba[index + 2],
+                                rgba[index + 3],
+                            )
+                        },
+                    )
+                });
+            }))
+        }
+        format => {
+            let alpha_index = image.alpha_index.filter(|_| unpremultiply);
+            let rgb = image
+                .data
+                .chunks(image.num_channels)
+                .flat_map(|pixel| {
+                    let scale = match alpha_index.map(|alpha_index| pixel[alpha_index]) {
+                        Some(alpha) if 0.0 != alpha => 1. / alpha,
+                        _ => 1.,
+                    };
+                    [
+                        pixel[rgb_index] * scale,
+                        pixel[rgb_index + 1] * scale,
+                        pixel[rgb_index + 2] * scale,
+                    ]
+                })
+                .collect::<Vec<_>>();
+            let write = if FileFormat::Hdr == format {
+                hdr::write
+            } else {
+                pfm::write
+            };
+
+            Some(std::thread::spawn(move || {
+                write_to_file(&file_name, |file_name| {
+                    Ok(write(file_name, width, height, &rgb)?)
+                });
+            }))
+        }
+    }
+}
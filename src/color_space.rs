@@ -0,0 +1,123 @@
+//! Color spaces the renderer can be working in and their matching EXR
+//! `chromaticities`/`adoptedNeutral` attributes.
+use exr::{math::Vec2, meta::attribute::Chromaticities};
+
+/// The CIE 1931 xy coordinates of the D65 white point.
+const D65: Vec2<f32> = Vec2(0.3127, 0.3290);
+
+/// The CIE 1931 xy coordinates of the ACES white point (~D60).
+const ACES_WHITE: Vec2<f32> = Vec2(0.32168, 0.33767);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorSpace {
+    /// sRGB and Rec.709 share their primaries and white point.
+    Rec709,
+    /// ACES AP1 primaries.
+    AcesCg,
+    /// ACES AP0 primaries.
+    Aces2065_1,
+    Rec2020,
+}
+
+impl ColorSpace {
+    /// Parses the value of the `colorspace` parameter.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "srgb" | "rec709" | "rec.709" => Some(ColorSpace::Rec709),
+            "acescg" | "ap1" => Some(ColorSpace::AcesCg),
+            "aces2065-1" | "aces" | "ap0" => Some(ColorSpace::Aces2065_1),
+            "rec2020" | "rec.2020" => Some(ColorSpace::Rec2020),
+            _ => None,
+        }
+    }
+
+    pub fn chromaticities(&self) -> Chromaticities {
+        match self {
+            ColorSpace::Rec709 => Chromaticities {
+                red: Vec2(0.64, 0.33),
+                green: Vec2(0.30, 0.60),
+                blue: Vec2(0.15, 0.06),
+                white: D65,
+            },
+            ColorSpace::AcesCg => Chromaticities {
+                red: Vec2(0.713, 0.293),
+                green: Vec2(0.165, 0.830),
+                blue: Vec2(0.128, 0.044),
+                white: ACES_WHITE,
+            },
+            ColorSpace::Aces2065_1 => Chromaticities {
+                red: Vec2(0.7347, 0.2653),
+                green: Vec2(0.0, 1.0),
+                blue: Vec2(0.0001, -0.0770),
+                white: ACES_WHITE,
+            },
+            ColorSpace::Rec2020 => Chromaticities {
+                red: Vec2(0.708, 0.292),
+                green: Vec2(0.170, 0.797),
+                blue: Vec2(0.131, 0.046),
+                white: D65,
+            },
+        }
+    }
+
+    /// The white point pixels should be considered neutral against.
+    pub fn adopted_neutral(&self) -> Vec2<f32> {
+        self.chromaticities().white
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn from_name() {
+        assert_eq!(Some(ColorSpace::Rec709), ColorSpace::from_name("sRGB"));
+        assert_eq!(Some(ColorSpace::AcesCg), ColorSpace::from_name("ACEScg"));
+        assert_eq!(
+            Some(ColorSpace::Aces2065_1),
+            ColorSpace::from_name("ACES2065-1")
+        );
+        assert_eq!(Some(ColorSpace::Rec2020), ColorSpace::from_name("rec.2020"));
+        assert_eq!(None, ColorSpace::from_name("xyz"));
+    }
+
+    #[test]
+    fn attributes() {
+        let samples = rgba(1, 1, |_, _| [1.; 4]);
+
+        let aces_cg = file_name("acescg.exr");
+        render(
+            &aces_cg,
+            1,
+            1,
+            &[
+                ("colorspace", Value::String(&["acescg"])),
+                ("white_luminance", Value::Float(&[100.])),
+            ],
+            &samples,
+        );
+        let image = read_exr(&aces_cg);
+        assert_eq!(
+            Some(ColorSpace::AcesCg.chromaticities()),
+            image.attributes.chromaticities
+        );
+        let layer_attributes = &image.layers[0].attributes;
+        assert_eq!(Some(ACES_WHITE), layer_attributes.adopted_neutral);
+        assert_eq!(Some(100.), layer_attributes.white_luminance);
+
+        // Unknown color spaces write no chromaticities.
+        let unknown = file_name("unknown.exr");
+        render(
+            &unknown,
+            1,
+            1,
+            &[("colorspace", Value::String(&["xyz"]))],
+            &samples,
+        );
+        let image = read_exr(&unknown);
+        assert_eq!(None, image.attributes.chromaticities);
+        assert_eq!(None, image.layers[0].attributes.adopted_neutral);
+    }
+}
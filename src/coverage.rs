@@ -0,0 +1,57 @@
+//! Which pixels of the frame have arrived.
+//!
+//! Progressive renders send every pixel once per pass. Counting the
+//! pixels of each bucket would then make a frame look complete long
+//! before its last bucket arrived.
+
+/// One bit per pixel, in rows of `width`.
+#[derive(Debug)]
+pub struct Coverage {
+    width: usize,
+    bits: Vec<u64>,
+}
+
+impl Coverage {
+    pub fn new(width: usize, height: usize) -> Self {
+        Coverage {
+            width,
+            bits: vec![0; (width * height).div_ceil(64)],
+        }
+    }
+
+    /// Marks the pixels `x_min..x_max` × `y_min..y_max` as arrived.
+    ///
+    /// Returns how many of them had not arrived before.
+    pub fn add(&mut self, x_min: usize, x_max: usize, y_min: usize, y_max: usize) -> usize {
+        let mut added = 0;
+
+        for y in y_min..y_max {
+            for index in y * self.width + x_min..y * self.width + x_max {
+                let (word, bit) = (index / 64, 1u64 << (index % 64));
+                if 0 == self.bits[word] & bit {
+                    self.bits[word] |= bit;
+                    added += 1;
+                }
+            }
+        }
+
+        added
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_pixels_once() {
+        let mut coverage = Coverage::new(100, 10);
+
+        assert_eq!(50, coverage.add(0, 10, 0, 5));
+        // Overlaps the first bucket in 5 × 5 pixels.
+        assert_eq!(75, coverage.add(5, 15, 0, 10));
+        assert_eq!(25, coverage.add(0, 15, 0, 10));
+        assert_eq!(0, coverage.add(0, 15, 0, 10));
+        assert_eq!(850, coverage.add(0, 100, 0, 10));
+    }
+}
@@ -0,0 +1,64 @@
+//! Checksums of written files.
+
+/// CRC-32 (IEEE 802.3), as used by zip & PNG, of bytes fed in pieces.
+pub struct Crc32 {
+    table: [u32; 256],
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        let mut table = [0u32; 256];
+        table.iter_mut().enumerate().for_each(|(n, entry)| {
+            *entry = (0..8).fold(n as u32, |c, _| {
+                if 0 != c & 1 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                }
+            })
+        });
+
+        Crc32 { table, crc: !0 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        let table = &self.table;
+        self.crc = bytes.iter().fold(self.crc, |crc, &byte| {
+            table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        });
+    }
+
+    /// The checksum of the bytes fed so far.
+    pub fn value(&self) -> u32 {
+        !self.crc
+    }
+}
+
+/// CRC-32 of `bytes`.
+#[cfg(test)]
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xcbf4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn in_pieces() {
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"");
+        crc.update(b"56789");
+        assert_eq!(0xcbf4_3926, crc.value());
+    }
+}
@@ -0,0 +1,223 @@
+//! 8 & 16 bit versions of the beauty layer written next to the EXR,
+//! e.g. for dailies.
+use crate::{jpeg, png, tiff, ImageData};
+
+/// Quality of JPEG outputs.
+const JPEG_QUALITY: u8 = 90;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Png,
+    Jpeg,
+    Tiff,
+}
+
+impl Format {
+    /// Parses an entry of the `extra_outputs` parameter.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Some(Format::Png),
+            "jpg" | "jpeg" => Some(Format::Jpeg),
+            "tif" | "tiff" => Some(Format::Tiff),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+            Format::Tiff => "tif",
+        }
+    }
+
+    /// The RenderMan style quantization used if none is given.
+    fn default_quantize(&self) -> [f32; 4] {
+        match self {
+            Format::Png | Format::Jpeg => [0., 255., 0., 255.],
+            Format::Tiff => [0., 65535., 0., 65535.],
+        }
+    }
+}
+
+/// Writes the `extra_outputs` of `image`, next to `file_name` with the
+/// extension of their format.
+pub fn write(image: &ImageData, file_name: &str) {
+    let (rgb_index, alpha_index) = match (image.rgb_index, image.alpha_index) {
+        (Some(rgb_index), Some(alpha_index)) => (rgb_index, alpha_index),
+        _ => {
+            eprintln!("[r-display] not writing extra outputs – missing rgb and/or alpha data");
+            return;
+        }
+    };
+
+    for format in &image.extra_outputs {
+        let file_name = std::path::Path::new(file_name)
+            .with_extension(format.extension())
+            .to_string_lossy()
+            .into_owned();
+
+        let file_name = match image.overwrite.file_name(&file_name) {
+            Some(file_name) => file_name,
+            None => {
+                eprintln!("[r-display] {} exists; not overwriting it", file_name);
+                continue;
+            }
+        };
+
+        let mut quantize = image.quantize.unwrap_or_else(|| format.default_quantize());
+        // JPEGs only have eight bits.
+        if Format::Jpeg == *format && 255. < quantize[3] {
+            quantize = format.default_quantize();
+        }
+        let bit_depth = if 255. < quantize[3] { 16 } else { 8 };
+
+        // JPEGs have no alpha; their color is shown over black.
+        let num_channels = if Format::Jpeg == *format { 3 } else { 4 };
+        let samples = samples(image, rgb_index, alpha_index, num_channels, quantize);
+
+        println!("[r-display] writing {} ...", file_name);
+
+        crate::write_to_file(&file_name, |file_name| {
+            match format {
+                Format::Png => png::write(
+                    file_name,
+                    image.width,
+                    image.height,
+                    num_channels,
+                    bit_depth,
+                    &samples,
+                ),
+                Format::Jpeg => jpeg::write(
+                    file_name,
+                    image.width,
+                    image.height,
+                    &samples
+                        .iter()
+                        .map(|&sample| sample as u8)
+                        .collect::<Vec<_>>(),
+                    JPEG_QUALITY,
+                ),
+                Format::Tiff => tiff::write(
+                    file_name,
+                    image.width,
+                    image.height,
+                    num_channels,
+                    bit_depth,
+                    &samples,
+                ),
+            }?;
+            Ok(())
+        });
+    }
+}
+
+/// The viewing transformed RGB(A) samples of the image, quantized with
+/// RenderMan's `[zero, one, min, max]` convention and dithered.
+///
+/// With four channels the color is unpremultiplied.
+fn samples(
+    image: &ImageData,
+    rgb_index: usize,
+    alpha_index: usize,
+    num_channels: usize,
+    [zero, one, min, max]: [f32; 4],
+) -> Vec<u16> {
+    let mut samples = Vec::with_capacity(num_channels * image.width * image.height);
+
+    image
+        .data
+        .chunks(image.num_channels)
+        .enumerate()
+        .for_each(|(i, pixel)| {
+            let alpha = pixel[alpha_index];
+            let scale = match (image.premultiply, num_channels) {
+                // Ignore pixels whose alpha is zero.
+                (true, 4) if 0.0 != alpha => 1. / alpha,
+                (false, 3) => alpha,
+                _ => 1.,
+            };
+            let rgb = image.view_transform.apply([
+                pixel[rgb_index] * scale,
+                pixel[rgb_index + 1] * scale,
+                pixel[rgb_index + 2] * scale,
+            ]);
+
+            [rgb[0], rgb[1], rgb[2], alpha]
+                .iter()
+                .take(num_channels)
+                .enumerate()
+                .for_each(|(channel, &value)| {
+                    let dither = image.dither * noise(num_channels * i + channel);
+                    samples.push(
+                        (zero + value * (one - zero) + dither)
+                            .round()
+                            .clamp(min, max) as u16,
+                    );
+                });
+        });
+
+    samples
+}
+
+/// Reproducible white noise in -1..1.
+fn noise(index: usize) -> f32 {
+    // The finalizer of MurmurHash3.
+    let mut hash = index as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^= hash >> 16;
+
+    hash as f32 / u32::MAX as f32 * 2. - 1.
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{png, test_util::*};
+
+    #[test]
+    fn quantized_png() {
+        let file_name = file_name("extra_output.exr");
+        render(
+            &file_name,
+            2,
+            1,
+            &[
+                ("extra_outputs", Value::String(&["png jpeg"])),
+                ("view_transform", Value::String(&["linear"])),
+                ("dither", Value::Float(&[0.])),
+            ],
+            // Premultiplied.
+            &[0.25, 0.5, 1., 0.5, 0., 0., 0., 0.],
+        );
+
+        let png_file_name = file_name.replace(".exr", ".png");
+        assert_eq!(
+            (2, 1, 4, 8, vec![128, 255, 255, 128, 0, 0, 0, 0]),
+            png::tests::read(&png_file_name)
+        );
+        assert!(std::path::Path::new(&file_name.replace(".exr", ".jpg")).exists());
+    }
+
+    #[test]
+    fn sixteen_bit_png() {
+        let file_name = file_name("extra_output_16.exr");
+        render(
+            &file_name,
+            1,
+            1,
+            &[
+                ("extra_outputs", Value::String(&["png"])),
+                ("view_transform", Value::String(&["linear"])),
+                ("quantize", Value::Float(&[0., 65535., 0., 65535.])),
+                ("dither", Value::Float(&[0.])),
+            ],
+            &[0.5, 0.25, 0., 1.],
+        );
+
+        let (.., bit_depth, samples) = png::tests::read(&file_name.replace(".exr", ".png"));
+        assert_eq!((16, vec![32768, 16384, 0, 65535]), (bit_depth, samples));
+    }
+}
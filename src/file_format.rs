@@ -0,0 +1,98 @@
+//! Formats the image itself can be written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileFormat {
+    Exr,
+    /// Radiance RGBE.
+    Hdr,
+    /// Portable float map.
+    Pfm,
+}
+
+impl FileFormat {
+    /// Parses the value of the `format` parameter.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "exr" | "openexr" => Some(FileFormat::Exr),
+            "hdr" | "rgbe" | "radiance" | "pic" => Some(FileFormat::Hdr),
+            "pfm" => Some(FileFormat::Pfm),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FileFormat::Exr => "EXR",
+            FileFormat::Hdr => "HDR",
+            FileFormat::Pfm => "PFM",
+        }
+    }
+
+    /// The format matching the extension of `file_name`, EXR if there is
+    /// none we know.
+    pub fn from_file_name(file_name: &str) -> Self {
+        std::path::Path::new(file_name)
+            .extension()
+            .and_then(|extension| Self::from_name(&extension.to_string_lossy()))
+            .unwrap_or(FileFormat::Exr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn from_file_name() {
+        assert_eq!(FileFormat::Exr, FileFormat::from_file_name("beauty.exr"));
+        assert_eq!(FileFormat::Hdr, FileFormat::from_file_name("beauty.HDR"));
+        assert_eq!(
+            FileFormat::Hdr,
+            FileFormat::from_file_name("dir.pfm/beauty.pic")
+        );
+        assert_eq!(FileFormat::Pfm, FileFormat::from_file_name("beauty.pfm"));
+        assert_eq!(FileFormat::Exr, FileFormat::from_file_name("beauty.tif"));
+        assert_eq!(FileFormat::Exr, FileFormat::from_file_name("beauty"));
+    }
+
+    /// Renders a pixel to `name` and returns the first bytes written.
+    fn magic(name: &str, parameters: &[(&str, Value)]) -> Vec<u8> {
+        let file_name = file_name(name);
+        render(&file_name, 1, 1, parameters, &[1., 1., 1., 1.]);
+
+        std::fs::read(&file_name).unwrap()[..4].to_vec()
+    }
+
+    #[test]
+    fn selection() {
+        let exr = vec![0x76, 0x2f, 0x31, 0x01];
+
+        assert_eq!(exr, magic("image.exr", &[]));
+        assert_eq!(b"#?RA", &magic("image.hdr", &[])[..]);
+        assert_eq!(b"PF\n1", &magic("image.pfm", &[])[..]);
+        // The extension of the expanded file name counts.
+        std::env::set_var("R_DISPLAY_TEST_EXTENSION", "hdr");
+        let expanded = file_name("expanded.hdr");
+        render(
+            &expanded.replace(".hdr", ".$R_DISPLAY_TEST_EXTENSION"),
+            1,
+            1,
+            &[],
+            &[1., 1., 1., 1.],
+        );
+        assert_eq!(b"#?RA", &std::fs::read(&expanded).unwrap()[..4]);
+        // The format parameter wins over the extension.
+        assert_eq!(
+            b"PF\n1",
+            &magic("image.exr", &[("format", Value::String(&["pfm"]))])[..]
+        );
+        assert_eq!(
+            exr,
+            magic("image.hdr", &[("format", Value::String(&["OpenEXR"]))])
+        );
+        assert_eq!(
+            exr,
+            magic("image.hdr", &[("format", Value::String(&["bmp"]))])
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Expansion of output file name templates.
+use std::path::Path;
+
+/// Expands the tokens in a file name `template`.
+///
+/// * `<name>` is replaced with the value of the token `name` from
+///   `tokens`.
+/// * A run of `#` characters is replaced with the `frame` token, zero
+///   padded to the length of the run.
+/// * `$NAME` and `${NAME}` are replaced with the value of the
+///   environment variable `NAME`.
+///
+/// Unknown tokens and environment variables are left as they are.
+pub fn expand(template: &str, tokens: &[(&str, String)], frame: Option<i32>) -> String {
+    let mut file_name = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '<' => {
+                let token: String = chars.clone().take_while(|&c| '>' != c).collect();
+
+                match tokens.iter().find(|(name, _)| *name == token) {
+                    // Only replace if the token is closed.
+                    Some((_, value)) if chars.clone().nth(token.len()) == Some('>') => {
+                        file_name.push_str(value);
+                        chars.nth(token.len());
+                    }
+                    _ => file_name.push(c),
+                }
+            }
+            '#' if frame.is_some() => {
+                let mut width = 1;
+                while chars.peek() == Some(&'#') {
+                    chars.next();
+                    width += 1;
+                }
+                file_name.push_str(&format!("{:0width$}", frame.unwrap(), width = width));
+            }
+            '$' => {
+                let braced = chars.peek() == Some(&'{');
+                if braced {
+                    chars.next();
+                }
+
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_alphanumeric() || '_' == c {
+                        name.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+
+                let closed = !braced || chars.peek() == Some(&'}');
+
+                match std::env::var(&name) {
+                    Ok(value) if !name.is_empty() && closed => {
+                        if braced {
+                            chars.next();
+                        }
+                        file_name.push_str(&value);
+                    }
+                    _ => {
+                        file_name.push('$');
+                        if braced {
+                            file_name.push('{');
+                        }
+                        file_name.push_str(&name);
+                    }
+                }
+            }
+            _ => file_name.push(c),
+        }
+    }
+
+    file_name
+}
+
+/// Creates the directory `file_name` is going to be written to, if it
+/// does not exist.
+pub fn create_parent_directory(file_name: &str) {
+    if let Some(parent) = Path::new(file_name).parent() {
+        if !parent.as_os_str().is_empty() && !parent.exists() {
+            std::fs::create_dir_all(parent).unwrap_or_else(|e| {
+                eprintln!(
+                    "[r-display] could not create directory {}: {}",
+                    parent.display(),
+                    e
+                )
+            });
+        }
+    }
+}
+
+/// A sibling of `file_name` to write to before renaming the result to
+/// `file_name`.
+///
+/// This way a crash during writing never leaves a truncated image behind
+/// that looks like a finished one.
+pub fn temporary(file_name: &str) -> String {
+    let path = Path::new(file_name);
+
+    path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default(),
+        std::process::id()
+    ))
+    .to_string_lossy()
+    .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, *};
+
+    #[test]
+    fn tokens() {
+        let tokens = [
+            ("layer", "diffuse".to_string()),
+            ("frame", "0012".to_string()),
+        ];
+
+        assert_eq!(
+            "diffuse/beauty.0012.exr",
+            expand("<layer>/beauty.<frame>.exr", &tokens, Some(12))
+        );
+        assert_eq!("b.012.exr", expand("b.###.exr", &tokens, Some(12)));
+        assert_eq!("b.12345.exr", expand("b.#.exr", &tokens, Some(12345)));
+        assert_eq!("b.-07.exr", expand("b.###.exr", &tokens, Some(-7)));
+        // Unknown & unclosed tokens are left alone, as are hashes
+        // without a frame.
+        assert_eq!("<camera>.exr", expand("<camera>.exr", &tokens, None));
+        assert_eq!("<layer.exr", expand("<layer.exr", &tokens, None));
+        assert_eq!("b.##.exr", expand("b.##.exr", &tokens, None));
+    }
+
+    #[test]
+    fn environment_variables() {
+        std::env::set_var("R_DISPLAY_TEST_SHOT", "sh010");
+        std::env::remove_var("R_DISPLAY_TEST_UNSET");
+
+        assert_eq!(
+            "sh010/sh010_b.exr",
+            expand(
+                "$R_DISPLAY_TEST_SHOT/${R_DISPLAY_TEST_SHOT}_b.exr",
+                &[],
+                None
+            )
+        );
+        assert_eq!(
+            "$R_DISPLAY_TEST_UNSET/${R_DISPLAY_TEST_UNSET}.exr",
+            expand(
+                "$R_DISPLAY_TEST_UNSET/${R_DISPLAY_TEST_UNSET}.exr",
+                &[],
+                None
+            )
+        );
+        assert_eq!(
+            "${R_DISPLAY_TEST_SHOT.exr",
+            expand("${R_DISPLAY_TEST_SHOT.exr", &[], None)
+        );
+        assert_eq!("$.exr", expand("$.exr", &[], None));
+    }
+
+    #[test]
+    fn missing_directories_are_created() {
+        let template = test_util::file_name("<layer>/<view>/beauty.####.exr");
+        render(
+            &template,
+            1,
+            1,
+            &[
+                ("frame", Value::Float(&[7.])),
+                ("view", Value::String(&["left"])),
+            ],
+            &rgba(1, 1, |_, _| [1.; 4]),
+        );
+
+        let file_name = template
+            .replace("<layer>", "RGBA")
+            .replace("<view>", "left")
+            .replace("####", "0007");
+        assert!(Path::new(&file_name).exists());
+    }
+}
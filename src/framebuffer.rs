@@ -0,0 +1,311 @@
+//! The pixels of an image, either on the heap or in a named POSIX
+//! shared-memory segment other processes can map to watch the render.
+//!
+//! A shared-memory segment starts with a header (all numbers in native
+//! byte order):
+//!
+//! | Offset | Type       | Content                                   |
+//! |--------|------------|-------------------------------------------|
+//! | 0      | `[u8; 8]`  | Magic, `r-dspy\0\0`                       |
+//! | 8      | `u32`      | Header version, currently 1               |
+//! | 12     | `u32`      | Width                                     |
+//! | 16     | `u32`      | Height                                    |
+//! | 20     | `u32`      | Number of channels                        |
+//! | 24     | `u64`      | Offset of the pixels from the start       |
+//! | 32     | `u64`      | Number of pixels received so far          |
+//! | 40     | `u64`      | Total number of pixels                    |
+//! | 48     | `[u8]`     | Channel names, each terminated by a zero  |
+//!
+//! The pixels follow at the given offset as interleaved `f32` channels.
+use std::ops::{Deref, DerefMut};
+#[cfg(unix)]
+use std::{ffi::CString, sync::atomic::AtomicU64, sync::atomic::Ordering};
+
+#[cfg(unix)]
+const MAGIC: &[u8; 8] = b"r-dspy\0\0";
+#[cfg(unix)]
+const VERSION: u32 = 1;
+#[cfg(unix)]
+const FINISHED_PIXELS_OFFSET: usize = 32;
+#[cfg(unix)]
+const CHANNEL_NAMES_OFFSET: usize = 48;
+/// Pixels start at a multiple of this.
+#[cfg(unix)]
+const ALIGNMENT: usize = 64;
+
+#[derive(Debug)]
+pub enum Framebuffer {
+    Heap(Vec<f32>),
+    #[cfg(unix)]
+    Shared(SharedMemory),
+}
+
+impl Framebuffer {
+    /// Creates a framebuffer in the shared-memory segment `name`,
+    /// falling back to the heap if this fails.
+    pub fn shared(name: &str, width: usize, height: usize, channel_names: &[String]) -> Self {
+        #[cfg(unix)]
+        match SharedMemory::create(name, width, height, channel_names) {
+            Ok(shared_memory) => return Framebuffer::Shared(shared_memory),
+            Err(e) => eprintln!("[r-display] could not create shared memory {}: {}", name, e),
+        }
+        #[cfg(not(unix))]
+        eprintln!(
+            "[r-display] shared memory {} is not supported on this platform",
+            name
+        );
+
+        Framebuffer::Heap(vec![0.0f32; width * height * channel_names.len()])
+    }
+
+    pub fn is_shared(&self) -> bool {
+        !matches!(self, Framebuffer::Heap(_))
+    }
+
+    /// Publishes the number of pixels received so far.
+    pub fn set_finished_pixels(&self, _finished_pixels: usize) {
+        #[cfg(unix)]
+        if let Framebuffer::Shared(shared_memory) = self {
+            shared_memory.set_finished_pixels(_finished_pixels);
+        }
+    }
+}
+
+impl Deref for Framebuffer {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        match self {
+            Framebuffer::Heap(data) => data,
+            #[cfg(unix)]
+            Framebuffer::Shared(shared_memory) => unsafe {
+                std::slice::from_raw_parts(
+                    shared_memory.address.add(shared_memory.data_offset) as *const f32,
+                    shared_memory.num_samples,
+                )
+            },
+        }
+    }
+}
+
+impl DerefMut for Framebuffer {
+    fn deref_mut(&mut self) -> &mut [f32] {
+        match self {
+            Framebuffer::Heap(data) => data,
+            #[cfg(unix)]
+            Framebuffer::Shared(shared_memory) => unsafe {
+                std::slice::from_raw_parts_mut(
+                    shared_memory.address.add(shared_memory.data_offset) as *mut f32,
+                    shared_memory.num_samples,
+                )
+            },
+        }
+    }
+}
+
+/// A mapped POSIX shared-memory segment. It is unlinked when dropped;
+/// processes that still have it mapped keep their view of it.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct SharedMemory {
+    name: CString,
+    address: *mut u8,
+    len: usize,
+    data_offset: usize,
+    num_samples: usize,
+}
+
+// The mapping is owned by exactly one display instance and only
+// mutated through `&mut`, like a `Vec`.
+#[cfg(unix)]
+unsafe impl Send for SharedMemory {}
+#[cfg(unix)]
+unsafe impl Sync for SharedMemory {}
+
+#[cfg(unix)]
+impl SharedMemory {
+    fn create(
+        name: &str,
+        width: usize,
+        height: usize,
+        channel_names: &[String],
+    ) -> std::io::Result<Self> {
+        // POSIX wants names to start with a slash.
+        let name = if name.starts_with('/') {
+            name.to_string()
+        } else {
+            format!("/{}", name)
+        };
+        let name = CString::new(name)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+        let names_len: usize = channel_names.iter().map(|name| name.len() + 1).sum();
+        let data_offset = (CHANNEL_NAMES_OFFSET + names_len).next_multiple_of(ALIGNMENT);
+        let num_samples = width * height * channel_names.len();
+        let len = data_offset + num_samples * std::mem::size_of::<f32>();
+
+        // A segment left behind, e.g. by a crashed render, may be larger
+        // and hold old pixels. Replace it with a fresh one; viewers still
+        // mapping it keep the old pixels until they reopen the segment.
+        unsafe { libc::shm_unlink(name.as_ptr()) };
+        let fd = unsafe {
+            libc::shm_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0o644 as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let address = unsafe {
+            if 0 != libc::ftruncate(fd, len as _) {
+                let e = std::io::Error::last_os_error();
+                libc::close(fd);
+                libc::shm_unlink(name.as_ptr());
+                return Err(e);
+            }
+
+            let address = libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            // The mapping stays valid after closing the descriptor.
+            libc::close(fd);
+
+            if libc::MAP_FAILED == address {
+                let e = std::io::Error::last_os_error();
+                libc::shm_unlink(name.as_ptr());
+                return Err(e);
+            }
+
+            address as *mut u8
+        };
+
+        // The fresh segment is zeroed; write the header.
+        let header = unsafe { std::slice::from_raw_parts_mut(address, data_offset) };
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_ne_bytes());
+        header[12..16].copy_from_slice(&(width as u32).to_ne_bytes());
+        header[16..20].copy_from_slice(&(height as u32).to_ne_bytes());
+        header[20..24].copy_from_slice(&(channel_names.len() as u32).to_ne_bytes());
+        header[24..32].copy_from_slice(&(data_offset as u64).to_ne_bytes());
+        header[40..48].copy_from_slice(&((width * height) as u64).to_ne_bytes());
+        let mut offset = CHANNEL_NAMES_OFFSET;
+        for channel_name in channel_names {
+            header[offset..offset + channel_name.len()].copy_from_slice(channel_name.as_bytes());
+            header[offset + channel_name.len()] = 0;
+            offset += channel_name.len() + 1;
+        }
+
+        Ok(SharedMemory {
+            name,
+            address,
+            len,
+            data_offset,
+            num_samples,
+        })
+    }
+
+    fn set_finished_pixels(&self, finished_pixels: usize) {
+        // The offset is 8 byte aligned within the page aligned mapping.
+        unsafe { &*(self.address.add(FINISHED_PIXELS_OFFSET) as *const AtomicU64) }
+            .store(finished_pixels as _, Ordering::Release);
+    }
+}
+
+#[cfg(unix)]
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.address as *mut _, self.len);
+            libc::shm_unlink(self.name.as_ptr());
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn shared_memory() {
+        let name = format!("r-display-test-{}", std::process::id());
+        let display = Display::open(
+            &file_name("shared_memory.exr"),
+            4,
+            2,
+            &RGBA,
+            &[("shared_memory", Value::String(&[&name]))],
+        )
+        .unwrap();
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            display.data(1, 1, 2, 1, &[1., 2., 3., 4., 5., 6., 7., 8.])
+        );
+
+        // Linux maps POSIX shared memory to files in /dev/shm.
+        let segment = std::fs::read(format!("/dev/shm/{}", name)).unwrap();
+        let u32_at =
+            |offset: usize| u32::from_ne_bytes(segment[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_ne_bytes(segment[offset..offset + 8].try_into().unwrap());
+
+        assert_eq!(MAGIC, &segment[..8]);
+        assert_eq!(
+            [VERSION, 4, 2, 4],
+            [u32_at(8), u32_at(12), u32_at(16), u32_at(20)]
+        );
+        assert_eq!([2, 8], [u64_at(FINISHED_PIXELS_OFFSET), u64_at(40)]);
+        assert_eq!(
+            b"r\0g\0b\0a\0",
+            &segment[CHANNEL_NAMES_OFFSET..CHANNEL_NAMES_OFFSET + 8]
+        );
+
+        let data_offset = u64_at(24) as usize;
+        assert_eq!(0, data_offset % ALIGNMENT);
+        let pixels = segment[data_offset..]
+            .chunks(4)
+            .map(|bytes| f32::from_ne_bytes(bytes.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(32, pixels.len());
+        assert_eq!([1., 2., 3., 4., 5., 6., 7., 8.], pixels[20..28]);
+
+        display.close();
+        // The segment is gone with the display.
+        assert!(!std::path::Path::new(&format!("/dev/shm/{}", name)).exists());
+    }
+
+    #[test]
+    fn leftover_segment_is_replaced() {
+        let name = format!("r-display-leftover-{}", std::process::id());
+        let path = format!("/dev/shm/{}", name);
+        std::fs::write(&path, vec![0xff; 4096]).unwrap();
+
+        let display = Display::open(
+            &file_name("leftover.exr"),
+            2,
+            2,
+            &RGBA,
+            &[("shared_memory", Value::String(&[&name]))],
+        )
+        .unwrap();
+
+        let segment = std::fs::read(&path).unwrap();
+        let data_offset = u64::from_ne_bytes(segment[24..32].try_into().unwrap()) as usize;
+        assert_eq!(data_offset + 16 * 4, segment.len());
+        assert!(segment[FINISHED_PIXELS_OFFSET..FINISHED_PIXELS_OFFSET + 8]
+            .iter()
+            .all(|&byte| 0 == byte));
+        assert!(segment[data_offset..].iter().all(|&byte| 0 == byte));
+
+        display.close();
+    }
+}
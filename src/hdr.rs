@@ -0,0 +1,291 @@
+//! A Radiance RGBE (`.hdr`) encoder using run-length encoded scanlines.
+use std::io::Write;
+
+/// Shorter runs are stored as literals.
+const MIN_RUN_LENGTH: usize = 4;
+/// Longest run or literal a single count byte can describe.
+const MAX_LENGTH: usize = 127;
+
+/// Writes `rgb`, interleaved RGB samples of `width` × `height` pixels.
+pub fn write(file_name: &str, width: usize, height: usize, rgb: &[f32]) -> std::io::Result<()> {
+    let mut hdr = Vec::with_capacity(4 * width * height);
+
+    write!(
+        hdr,
+        "#?RADIANCE\nSOFTWARE=r-display\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+
+    let mut scanline = vec![[0u8; 4]; width];
+    for row in rgb.chunks(3 * width).take(height) {
+        row.chunks(3)
+            .zip(scanline.iter_mut())
+            .for_each(|(pixel, rgbe_pixel)| *rgbe_pixel = rgbe([pixel[0], pixel[1], pixel[2]]));
+
+        // Only scanlines of this width can be run-length encoded.
+        if (8..0x8000).contains(&width) {
+            hdr.extend_from_slice(&[2, 2, (width >> 8) as u8, width as u8]);
+            // Each component is encoded separately.
+            for component in 0..4 {
+                let bytes = scanline
+                    .iter()
+                    .map(|rgbe_pixel| rgbe_pixel[component])
+                    .collect::<Vec<_>>();
+                run_length_encode(&mut hdr, &bytes);
+            }
+        } else {
+            scanline
+                .iter()
+                .for_each(|rgbe_pixel| hdr.extend_from_slice(rgbe_pixel));
+        }
+    }
+
+    std::fs::File::create(file_name)?.write_all(&hdr)
+}
+
+/// Converts to a shared exponent. Negative values & NaNs become zero.
+fn rgbe(rgb: [f32; 3]) -> [u8; 4] {
+    let rgb = rgb.map(|value| if 0. < value { value } else { 0. });
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+
+    if max < 1e-32 {
+        return [0; 4];
+    }
+
+    // The mantissa of `max` is in 0.5..1.
+    let mut exponent = max.log2().floor() as i32 + 1;
+    if 1. <= max / 2f32.powi(exponent) {
+        exponent += 1;
+    }
+    let exponent = exponent.clamp(-128, 127);
+    let scale = 256. / 2f32.powi(exponent);
+
+    [
+        (rgb[0] * scale).min(255.) as u8,
+        (rgb[1] * scale).min(255.) as u8,
+        (rgb[2] * scale).min(255.) as u8,
+        (exponent + 128) as u8,
+    ]
+}
+
+/// Appends `bytes` as runs (count + 128, byte) and literals (count,
+/// bytes).
+fn run_length_encode(hdr: &mut Vec<u8>, bytes: &[u8]) {
+    let mut start = 0;
+
+    while start < bytes.len() {
+        // Find the next run long enough to be worth it.
+        let mut run_start = start;
+        let mut run_length = 0;
+        while run_start < bytes.len() {
+            run_length = bytes[run_start..]
+                .iter()
+                .take(MAX_LENGTH)
+                .take_while(|&&byte| byte == bytes[run_start])
+                .count();
+            if MIN_RUN_LENGTH <= run_length {
+                break;
+            }
+            run_start += run_length;
+        }
+
+        // Whatever comes before it is stored literally.
+        while start < run_start {
+            let length = (run_start - start).min(MAX_LENGTH);
+            hdr.push(length as u8);
+            hdr.extend_from_slice(&bytes[start..start + length]);
+            start += length;
+        }
+
+        if run_start < bytes.len() {
+            hdr.extend_from_slice(&[128 + run_length as u8, bytes[run_start]]);
+            start = run_start + run_length;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::file_name;
+
+    /// Reverses `run_length_encode()` for `len` bytes, checking that no
+    /// count exceeds what a byte can describe.
+    fn run_length_decode(encoded: &mut &[u8], len: usize) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(len);
+
+        while bytes.len() < len {
+            let count = encoded[0] as usize;
+            assert!(0 < count && count != 128);
+            if 128 < count {
+                bytes.extend(std::iter::repeat(encoded[1]).take(count - 128));
+                *encoded = &encoded[2..];
+            } else {
+                bytes.extend_from_slice(&encoded[1..1 + count]);
+                *encoded = &encoded[1 + count..];
+            }
+        }
+        assert_eq!(len, bytes.len());
+
+        bytes
+    }
+
+    fn round_trip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::new();
+        run_length_encode(&mut encoded, bytes);
+
+        let mut remaining = encoded.as_slice();
+        assert_eq!(bytes, run_length_decode(&mut remaining, bytes.len()));
+        assert!(remaining.is_empty());
+
+        encoded
+    }
+
+    /// Reads an HDR as written by `write()`.
+    ///
+    /// Returns width, height, whether the scanlines were run-length
+    /// encoded & interleaved RGB samples.
+    fn read(file_name: &str) -> (usize, usize, bool, Vec<f32>) {
+        let hdr = std::fs::read(file_name).unwrap();
+
+        let header_end = hdr.windows(2).position(|w| b"\n\n" == w).unwrap();
+        let header = String::from_utf8_lossy(&hdr[..header_end]);
+        assert!(header.starts_with("#?RADIANCE\n"));
+        assert!(header.contains("\nFORMAT=32-bit_rle_rgbe"));
+
+        let resolution_end = header_end
+            + 2
+            + hdr[header_end + 2..]
+                .iter()
+                .position(|&b| b'\n' == b)
+                .unwrap();
+        let resolution = String::from_utf8_lossy(&hdr[header_end + 2..resolution_end]).into_owned();
+        let resolution = resolution.split(' ').collect::<Vec<_>>();
+        assert_eq!(("-Y", "+X"), (resolution[0], resolution[2]));
+        let height = resolution[1].parse::<usize>().unwrap();
+        let width = resolution[3].parse::<usize>().unwrap();
+
+        let mut data = &hdr[resolution_end + 1..];
+        let mut run_length_encoded = false;
+        let mut rgb = Vec::with_capacity(3 * width * height);
+
+        for _ in 0..height {
+            let scanline = if [2, 2] == data[..2] {
+                assert_eq!(width, (data[2] as usize) << 8 | data[3] as usize);
+                run_length_encoded = true;
+                data = &data[4..];
+
+                let components = (0..4)
+                    .map(|_| run_length_decode(&mut data, width))
+                    .collect::<Vec<_>>();
+                (0..width)
+                    .map(|x| {
+                        [
+                            components[0][x],
+                            components[1][x],
+                            components[2][x],
+                            components[3][x],
+                        ]
+                    })
+                    .collect::<Vec<_>>()
+            } else {
+                let scanline = data[..4 * width]
+                    .chunks(4)
+                    .map(|rgbe| [rgbe[0], rgbe[1], rgbe[2], rgbe[3]])
+                    .collect();
+                data = &data[4 * width..];
+                scanline
+            };
+
+            scanline.iter().for_each(|rgbe| {
+                let scale = if 0 == rgbe[3] {
+                    0.
+                } else {
+                    2f32.powi(rgbe[3] as i32 - 128 - 8)
+                };
+                rgb.extend(
+                    rgbe[..3]
+                        .iter()
+                        .map(|&mantissa| (mantissa as f32 + 0.5) * scale),
+                );
+            });
+        }
+        assert!(data.is_empty());
+
+        (width, height, run_length_encoded, rgb)
+    }
+
+    #[test]
+    fn run_length_encoding_limits() {
+        // A run one longer than a count can describe.
+        assert_eq!(vec![128 + 127, 5, 1, 5], round_trip(&[5; 128]));
+        assert_eq!(
+            vec![128 + 127, 5, 128 + 127, 5, 128 + 46, 5],
+            round_trip(&[5; 300])
+        );
+
+        // Literals longer than a count can describe.
+        let literal = (0..300).map(|i| i as u8).collect::<Vec<_>>();
+        let encoded = round_trip(&literal);
+        assert_eq!((127, 127, 46), (encoded[0], encoded[128], encoded[256]));
+        assert_eq!(300 + 3, encoded.len());
+
+        // Runs shorter than four bytes are stored literally.
+        assert_eq!(vec![6, 1, 2, 2, 2, 3, 3], round_trip(&[1, 2, 2, 2, 3, 3]));
+        assert_eq!(
+            vec![1, 1, 128 + 4, 2, 1, 3],
+            round_trip(&[1, 2, 2, 2, 2, 3])
+        );
+
+        round_trip(&[]);
+        round_trip(&[7]);
+    }
+
+    #[test]
+    fn widths() {
+        // Only widths of 8 to 32767 can be run-length encoded.
+        for (width, height, run_length_encoded) in [
+            (1, 3, false),
+            (7, 3, false),
+            (8, 3, true),
+            (33, 2, true),
+            (0x7fff, 1, true),
+            (0x8000, 1, false),
+        ] {
+            let rgb = (0..3 * width * height)
+                .map(|i| {
+                    if 0 == i % 5 {
+                        0.
+                    } else {
+                        (i % 97) as f32 / 10.
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let file_name = file_name("widths.hdr");
+            write(&file_name, width, height, &rgb).unwrap();
+
+            let read = read(&file_name);
+            assert_eq!(
+                (width, height, run_length_encoded),
+                (read.0, read.1, read.2)
+            );
+            // Within the precision of the largest component.
+            rgb.chunks(3)
+                .zip(read.3.chunks(3))
+                .for_each(|(written, read)| {
+                    let tolerance = written.iter().fold(0f32, |a, &b| a.max(b)) / 128.;
+                    written.iter().zip(read).for_each(|(&written, &read)| {
+                        assert!((written - read).abs() <= tolerance, "{} {}", written, read)
+                    });
+                });
+        }
+    }
+
+    #[test]
+    fn shared_exponent() {
+        assert_eq!([0; 4], rgbe([0., -1., f32::NAN]));
+        assert_eq!([128, 64, 0, 129], rgbe([1., 0.5, 0.]));
+        assert_eq!([128, 0, 0, 128 + 11], rgbe([1024., 0., 0.]));
+    }
+}
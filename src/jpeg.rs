@@ -0,0 +1,114 @@
+//! Writes baseline 8 bit RGB JPEGs.
+//!
+//! Colors are stored as YCbCr without chroma subsampling.
+use jpeg_encoder::{ColorType, Encoder, SamplingFactor};
+use std::{
+    convert::TryFrom,
+    io::{Error, ErrorKind},
+};
+
+/// Writes `rgb`, interleaved 8 bit RGB samples of `width` × `height`
+/// pixels, with the given `quality` (1 to 100).
+///
+/// JPEGs can not be wider or higher than 65535 pixels.
+pub fn write(
+    file_name: &str,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+    quality: u8,
+) -> std::io::Result<()> {
+    let (width, height) = match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} × {} pixels exceed the 65535 × 65535 of JPEGs",
+                    width, height
+                ),
+            ))
+        }
+    };
+
+    let mut encoder = Encoder::new_file(file_name, quality).map_err(Error::other)?;
+    encoder.set_sampling_factor(SamplingFactor::R_4_4_4);
+    encoder
+        .encode(rgb, width, height, ColorType::Rgb)
+        .map_err(Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::file_name;
+    use jpeg_decoder::{Decoder, PixelFormat};
+
+    /// Reads a JPEG.
+    ///
+    /// Returns width, height & the RGB samples.
+    fn read(file_name: &str) -> (usize, usize, Vec<u8>) {
+        let mut jpeg = Decoder::new(std::fs::File::open(file_name).unwrap());
+        let rgb = jpeg.decode().unwrap();
+        let info = jpeg.info().unwrap();
+        assert_eq!(PixelFormat::RGB24, info.pixel_format);
+
+        (info.width as _, info.height as _, rgb)
+    }
+
+    /// Mean & maximum absolute difference.
+    fn error(a: &[u8], b: &[u8]) -> (f32, u8) {
+        assert_eq!(a.len(), b.len());
+        let differences = a.iter().zip(b).map(|(a, b)| a.abs_diff(*b));
+        (
+            differences.clone().map(|d| d as f32).sum::<f32>() / a.len() as f32,
+            differences.max().unwrap(),
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        // Partial blocks at the right & bottom.
+        let (width, height) = (37, 21);
+        let rgb = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(7 * x) as u8, (12 * y) as u8, (255 - 3 * (x + y)) as u8]
+            })
+            .collect::<Vec<_>>();
+
+        let file_name = file_name("round_trip.jpg");
+        write(&file_name, width, height, &rgb, 90).unwrap();
+
+        let (read_width, read_height, read_rgb) = read(&file_name);
+        assert_eq!((width, height), (read_width, read_height));
+        let (mean, max) = error(&rgb, &read_rgb);
+        assert!(mean < 1.5 && max < 8, "mean {} max {}", mean, max);
+    }
+
+    #[test]
+    fn flat_color() {
+        let rgb = [200u8, 30, 90].repeat(16 * 16);
+
+        let file_name = file_name("flat_color.jpg");
+        write(&file_name, 16, 16, &rgb, 75).unwrap();
+
+        let (_, _, read_rgb) = read(&file_name);
+        let (_, max) = error(&rgb, &read_rgb);
+        assert!(max <= 2, "max {}", max);
+    }
+
+    #[test]
+    fn too_large() {
+        let file_name = file_name("too_large.jpg");
+        for (width, height) in [(65536, 1), (1, 65536)] {
+            assert_eq!(
+                ErrorKind::InvalidInput,
+                write(&file_name, width, height, &[], 90)
+                    .unwrap_err()
+                    .kind()
+            );
+        }
+        assert!(!std::path::Path::new(&file_name).exists());
+    }
+}
@@ -0,0 +1,274 @@
+//! Generation of the downsampled resolution levels stored in mip- and
+//! rip-mapped (tiled) EXRs.
+use exr::{
+    image::full::{FlatSamples, Levels, RipMaps, SampleBlock},
+    math::{RoundingMode, Vec2},
+    meta::{attribute::LevelMode, compute_level_count, mip_map_levels},
+};
+use rayon::prelude::*;
+
+/// Returns the source pixels (and their weights) that make up pixel
+/// `index` when resampling an axis of length `source_len` to
+/// `target_len`.
+///
+/// This is an area (box) filter: each source pixel is weighted by how
+/// much of it is covered by the footprint of the target pixel. Unlike
+/// point sampling or plain 2×2 averaging this also works for the odd
+/// level sizes `RoundingMode::Up` produces.
+fn footprint(
+    index: usize,
+    source_len: usize,
+    target_len: usize,
+) -> impl Iterator<Item = (usize, f32)> {
+    let scale = source_len as f32 / target_len as f32;
+    let start = index as f32 * scale;
+    let end = start + scale;
+
+    (start.floor() as usize..(end.ceil() as usize).min(source_len)).map(move |source| {
+        let coverage = end.min(source as f32 + 1.) - start.max(source as f32);
+        (source, coverage.max(0.) / scale)
+    })
+}
+
+/// Resamples the rows of a single channel to `width`.
+fn resample_horizontal(samples: &[f32], size: Vec2<usize>, width: usize) -> Vec<f32> {
+    let mut resampled = vec![0.0f32; width * size.height()];
+
+    resampled
+        .par_chunks_mut(width)
+        .zip(samples.par_chunks(size.width()))
+        .for_each(|(target_row, source_row)| {
+            target_row.iter_mut().enumerate().for_each(|(x, sample)| {
+                *sample = footprint(x, size.width(), width)
+                    .map(|(source, weight)| source_row[source] * weight)
+                    .sum();
+            })
+        });
+
+    resampled
+}
+
+/// Resamples the columns of a single channel to `height`.
+fn resample_vertical(samples: &[f32], size: Vec2<usize>, height: usize) -> Vec<f32> {
+    let mut resampled = vec![0.0f32; size.width() * height];
+
+    resampled
+        .par_chunks_mut(size.width())
+        .enumerate()
+        .for_each(|(y, target_row)| {
+            footprint(y, size.height(), height).for_each(|(source, weight)| {
+                let source_row = &samples[source * size.width()..(source + 1) * size.width()];
+                target_row
+                    .iter_mut()
+                    .zip(source_row)
+                    .for_each(|(sample, source_sample)| *sample += source_sample * weight);
+            })
+        });
+
+    resampled
+}
+
+fn resample(samples: &[f32], size: Vec2<usize>, new_size: Vec2<usize>) -> Vec<f32> {
+    let horizontal = resample_horizontal(samples, size, new_size.width());
+    resample_vertical(
+        &horizontal,
+        Vec2(new_size.width(), size.height()),
+        new_size.height(),
+    )
+}
+
+/// Computes all resolution levels of a single channel for the given
+/// `level_mode`.
+///
+/// The full resolution `samples` become the first level. Each mip level
+/// is filtered down from the previous one. Rip levels are filtered down
+/// horizontally first and then vertically from the full resolution.
+pub fn levels(
+    samples: Vec<f32>,
+    size: Vec2<usize>,
+    level_mode: LevelMode,
+    rounding_mode: RoundingMode,
+) -> Levels<FlatSamples<f32>> {
+    match level_mode {
+        LevelMode::Singular => Levels::Singular(SampleBlock {
+            resolution: size,
+            samples,
+        }),
+
+        LevelMode::MipMap => {
+            let mut levels = vec![SampleBlock {
+                resolution: size,
+                samples,
+            }];
+
+            mip_map_levels(rounding_mode, size)
+                .skip(1)
+                .for_each(|(_, level_size)| {
+                    let previous = levels.last().unwrap();
+                    let samples = resample(&previous.samples, previous.resolution, level_size);
+
+                    levels.push(SampleBlock {
+                        resolution: level_size,
+                        samples,
+                    });
+                });
+
+            Levels::Mip(levels)
+        }
+
+        LevelMode::RipMap => {
+            let level_count = Vec2(
+                compute_level_count(rounding_mode, size.width()),
+                compute_level_count(rounding_mode, size.height()),
+            );
+
+            let horizontal_levels: Vec<(usize, Vec<f32>)> =
+                mip_map_levels(rounding_mode, Vec2(size.width(), 1))
+                    .map(|(_, level_size)| {
+                        (
+                            level_size.width(),
+                            resample_horizontal(&samples, size, level_size.width()),
+                        )
+                    })
+                    .collect();
+
+            // Levels are ordered row by row, i.e. with the x level
+            // changing fastest.
+            let map_data = mip_map_levels(rounding_mode, Vec2(1, size.height()))
+                .flat_map(|(_, level_size)| {
+                    horizontal_levels
+                        .iter()
+                        .map(move |(width, horizontal)| SampleBlock {
+                            resolution: Vec2(*width, level_size.height()),
+                            samples: resample_vertical(
+                                horizontal,
+                                Vec2(*width, size.height()),
+                                level_size.height(),
+                            ),
+                        })
+                })
+                .collect();
+
+            Levels::Rip(RipMaps {
+                map_data,
+                level_count,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use exr::image::{
+        full::{self, ChannelData, SampleMaps},
+        read_options,
+    };
+
+    fn mean(samples: &[f32]) -> f32 {
+        samples.iter().sum::<f32>() / samples.len() as f32
+    }
+
+    #[test]
+    fn footprint_weights() {
+        // Each target pixel is a weighted average of what it covers.
+        for (source_len, target_len) in [(5, 3), (5, 2), (4, 2), (7, 1), (3, 3)] {
+            let mut coverage = vec![0.; source_len];
+            for index in 0..target_len {
+                let weights = footprint(index, source_len, target_len).collect::<Vec<_>>();
+                assert!((1. - weights.iter().map(|w| w.1).sum::<f32>()).abs() < 1e-5);
+                weights
+                    .iter()
+                    .for_each(|&(source, weight)| coverage[source] += weight);
+            }
+            // All source pixels count the same.
+            coverage
+                .iter()
+                .for_each(|&c| assert!((target_len as f32 / source_len as f32 - c).abs() < 1e-5));
+        }
+    }
+
+    #[test]
+    fn rip_map_order() {
+        let samples = (0..15).map(|i| i as f32).collect::<Vec<_>>();
+
+        match levels(samples, Vec2(5, 3), LevelMode::RipMap, RoundingMode::Up) {
+            Levels::Rip(rip_maps) => {
+                assert_eq!(Vec2(4, 3), rip_maps.level_count);
+                // The x level changes fastest.
+                let resolutions = rip_maps
+                    .map_data
+                    .iter()
+                    .map(|level| (level.resolution.width(), level.resolution.height()))
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    vec![
+                        (5, 3),
+                        (3, 3),
+                        (2, 3),
+                        (1, 3),
+                        (5, 2),
+                        (3, 2),
+                        (2, 2),
+                        (1, 2),
+                        (5, 1),
+                        (3, 1),
+                        (2, 1),
+                        (1, 1)
+                    ],
+                    resolutions
+                );
+                rip_maps
+                    .map_data
+                    .iter()
+                    .for_each(|level| assert!((7. - mean(&level.samples)).abs() < 1e-4));
+            }
+            _ => panic!("no rip maps"),
+        }
+    }
+
+    #[test]
+    fn mip_mapped_exr() {
+        for (rounding, sizes) in [
+            ("down", vec![(5, 3), (2, 1), (1, 1)]),
+            ("up", vec![(5, 3), (3, 2), (2, 1), (1, 1)]),
+        ] {
+            let file_name = file_name("levels.exr");
+            let samples = rgba(5, 3, |x, y| [x as f32, y as f32, 1., 1.]);
+            render(
+                &file_name,
+                5,
+                3,
+                &[
+                    ("levels", Value::String(&["mipmap"])),
+                    ("level_rounding", Value::String(&[rounding])),
+                ],
+                &samples,
+            );
+
+            let image = full::Image::read_from_file(&file_name, read_options::high()).unwrap();
+            let red = image.layers[0]
+                .channels
+                .iter()
+                .find(|channel| channel.name.eq("R"))
+                .unwrap();
+            match &red.content {
+                ChannelData::F32(SampleMaps::Flat(Levels::Mip(levels))) => {
+                    assert_eq!(
+                        sizes,
+                        levels
+                            .iter()
+                            .map(|level| (level.resolution.width(), level.resolution.height()))
+                            .collect::<Vec<_>>()
+                    );
+                    // The box filter keeps the average.
+                    levels[1..]
+                        .iter()
+                        .for_each(|level| assert!((2. - mean(&level.samples)).abs() < 1e-4));
+                }
+                _ => panic!("no mip maps"),
+            }
+        }
+    }
+}
@@ -1,20 +1,64 @@
 #![allow(unused_assignments)]
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
 use cgmath::prelude::*;
-use exr::prelude::rgba_image::*;
+use exr::{
+    error::UnitResult,
+    image::full,
+    math::RoundingMode,
+    meta::{
+        attribute::{EnvironmentMap, LevelMode},
+        Blocks,
+    },
+    prelude::rgba_image::*,
+};
 use rayon::prelude::*;
 use std::{
+    collections::HashMap,
     ffi::CStr,
     mem,
     os::raw::{c_char, c_int, c_void},
-    ptr,
+    time::{Duration, Instant},
 };
 
+mod checkpoint;
+mod color_space;
+mod coverage;
+mod crc;
+mod extra_output;
+mod file_format;
+mod file_name;
+mod framebuffer;
+mod hdr;
+mod jpeg;
+mod levels;
+mod manifest;
+mod on_abort;
+mod overwrite;
+mod pfm;
+mod png;
+mod progress;
+mod provenance;
+mod registry;
+mod sample_format;
+mod stream;
+mod tev;
+mod tiff;
+mod view_transform;
+
+#[cfg(test)]
+mod test_util;
+
+use color_space::ColorSpace;
+use file_format::FileFormat;
+use framebuffer::Framebuffer;
+use on_abort::OnAbort;
+use overwrite::Overwrite;
+use sample_format::{ByteOrder, SampleFormat};
+
 #[repr(C)]
 #[derive(Debug)]
 struct ImageData {
-    data: Vec<f32>,
-    offset: isize,
+    data: Framebuffer,
     width: usize,
     height: usize,
     pixel_aspect: f32,
@@ -28,22 +72,129 @@ struct ImageData {
     albedo_index: Option<usize>,
     normal_index: Option<usize>,
     renderer: Option<String>,
-    /*
-    data_window: [f32; 4],
-    display_window: [f32; 4],
-    screen_window_center: [f32; 2],
-    screen_window_width: f32,*/
+    channel_names: Vec<String>,
+    sample_formats: Vec<(SampleFormat, ByteOrder)>,
+    /// The pixels, if they are kept in the formats they arrive in
+    /// instead of `data`.
+    packed: Option<sample_format::Packed>,
+    layer_name: Option<String>,
+    data_window: IntegerBounds,
+    display_window: IntegerBounds,
+    color_space: Option<ColorSpace>,
+    white_luminance: Option<f32>,
+    exr_header: HashMap<Text, AttributeValue>,
+    comments: Option<String>,
+    open_time: Instant,
+    checkpoint_interval: Option<Duration>,
+    last_checkpoint: Instant,
+    /// The checkpoint being written.
+    checkpoint: Option<std::thread::JoinHandle<()>>,
+    render_time: Option<Duration>,
+    screen_window: Option<[f32; 4]>,
+    focal_length: Option<f32>,
+    f_stop: Option<f32>,
+    focal_distance: Option<f32>,
+    shutter: Option<[f32; 2]>,
+    film_back: Option<[f32; 2]>,
+    environment_map: Option<EnvironmentMap>,
+    view: Option<String>,
+    file_index: usize,
+    tev: Option<tev::Tev>,
+    stream: Option<stream::Stream>,
+    /// The renderer may send the same pixels more than once.
+    progressive: bool,
+    coverage: coverage::Coverage,
     premultiply: bool,
     compression: Compression,
     line_order: Option<LineOrder>,
     tile_size: Option<Vec2<usize>>,
+    levels: Option<LevelMode>,
+    level_rounding: RoundingMode,
     file_name: String,
+    format: FileFormat,
+    overwrite: Overwrite,
+    on_abort: OnAbort,
+    manifest: bool,
+    preview: bool,
+    extra_outputs: Vec<extra_output::Format>,
+    quantize: Option<[f32; 4]>,
+    dither: f32,
+    view_transform: view_transform::ViewTransform,
     denoise: f32,
     total_pixels: usize,
     finished_pixels: usize,
+    progress: progress::Progress,
 }
 
 impl ImageData {
+    /// The name of the layer given by the user or, failing that, the
+    /// name of the output layer the channels belong to.
+    fn layer_name(&self) -> Option<String> {
+        match &self.layer_name {
+            Some(layer_name) => Some(layer_name.clone()),
+            None if self.rgb_index.is_some() && self.alpha_index.is_some() => {
+                Some("RGBA".to_string())
+            }
+            // E.g. `albedo` for `albedo.000.r`.
+            None => self
+                .channel_names
+                .first()
+                .and_then(|name| name.split('.').next())
+                .filter(|name| 1 < name.len())
+                .map(|name| name.to_string()),
+        }
+    }
+
+    /// The sidecar file periodic checkpoints are written to, e.g.
+    /// `beauty_checkpoint.exr` for `beauty.exr`.
+    ///
+    /// Display instances sharing a file get one sidecar each.
+    fn checkpoint_file_name(&self) -> String {
+        let path = std::path::Path::new(&self.file_name);
+        let stem = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .map(|extension| format!(".{}", extension.to_string_lossy()))
+            .unwrap_or_default();
+
+        let checkpoint = if 0 == self.file_index {
+            format!("{}_checkpoint{}", stem, extension)
+        } else {
+            format!("{}_checkpoint{}{}", stem, self.file_index, extension)
+        };
+
+        path.with_file_name(checkpoint)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Whether the image can be written while it renders, i.e. nothing
+    /// needs the whole frame.
+    fn can_stream(&self) -> bool {
+        FileFormat::Exr == self.format
+            && self.rgb_index.is_some()
+            && self.alpha_index.is_some()
+            && self.denoise <= f32::EPSILON
+            && self.levels.is_none()
+            && self.tile_size.is_none()
+            && self.checkpoint_interval.is_none()
+            && !self.manifest
+            && !self.preview
+            && self.extra_outputs.is_empty()
+            && !self.data.is_shared()
+            && !self.progressive
+            // Display instances sharing a file are written together.
+            && !registry::is_shared(&self.file_name)
+    }
+
+    /// Whether the renderer stopped before all pixels arrived.
+    fn is_incomplete(&self) -> bool {
+        self.finished_pixels < self.total_pixels
+    }
+
     fn unpremultiply(&mut self) {
         if let (Some(alpha_index), Some(rgb_index)) = (self.alpha_index, self.rgb_index) {
             self.data
@@ -120,6 +271,82 @@ pub fn get_parameter<T: Copy>(
     None
 }
 
+/// Collects all user parameters named `exrheader_<name>` as EXR
+/// attributes called `<name>`.
+///
+/// This follows the convention of PRMan's `exrheader_` prefix.
+/// Strings, integers, floats, vectors and 3×3 or 4×4 matrices are
+/// supported.
+fn get_exr_header_attributes(
+    parameter: &[ndspy_sys::UserParameter],
+) -> HashMap<Text, AttributeValue> {
+    parameter
+        .iter()
+        .filter(|p| !p.value.is_null())
+        .filter_map(|p| {
+            let p_name = unsafe { CStr::from_ptr(p.name) }.to_string_lossy();
+            let name = Text::from(p_name.strip_prefix("exrheader_")?)?;
+
+            let len = p.valueCount as usize;
+
+            let value = match (p.valueType as u8, len) {
+                (b's', 1) => AttributeValue::Text(Text::from(
+                    unsafe { CStr::from_ptr(*(p.value as *const *const c_char)) }.to_string_lossy(),
+                )?),
+                (b's', _) => AttributeValue::TextVector(
+                    unsafe { std::slice::from_raw_parts(p.value as *const *const c_char, len) }
+                        .iter()
+                        .filter_map(|c_str_ptr| {
+                            Text::from(unsafe { CStr::from_ptr(*c_str_ptr) }.to_string_lossy())
+                        })
+                        .collect(),
+                ),
+                (b'i', _) => {
+                    let v = unsafe { std::slice::from_raw_parts(p.value as *const i32, len) };
+                    match len {
+                        1 => AttributeValue::I32(v[0]),
+                        2 => AttributeValue::IntVec2(Vec2(v[0], v[1])),
+                        3 => AttributeValue::IntVec3((v[0], v[1], v[2])),
+                        _ => {
+                            eprintln!(
+                                "[r-display] exrheader_{} has an unsupported length; ignoring",
+                                name
+                            );
+                            return None;
+                        }
+                    }
+                }
+                (b'f', _) => {
+                    let v = unsafe { std::slice::from_raw_parts(p.value as *const f32, len) };
+                    match len {
+                        1 => AttributeValue::F32(v[0]),
+                        2 => AttributeValue::FloatVec2(Vec2(v[0], v[1])),
+                        3 => AttributeValue::FloatVec3((v[0], v[1], v[2])),
+                        9 => AttributeValue::Matrix3x3(v.try_into().unwrap()),
+                        16 => AttributeValue::Matrix4x4(v.try_into().unwrap()),
+                        _ => {
+                            eprintln!(
+                                "[r-display] exrheader_{} has an unsupported length; ignoring",
+                                name
+                            );
+                            return None;
+                        }
+                    }
+                }
+                _ => {
+                    eprintln!(
+                        "[r-display] exrheader_{} has an unsupported type; ignoring",
+                        name
+                    );
+                    return None;
+                }
+            };
+
+            Some((name, value))
+        })
+        .collect()
+}
+
 #[no_mangle]
 pub extern "C" fn DspyImageOpen(
     image_handle_ptr: *mut ndspy_sys::PtDspyImageHandle,
@@ -131,7 +358,7 @@ pub extern "C" fn DspyImageOpen(
     parameter: *mut ndspy_sys::UserParameter,
     format_count: c_int,
     format: *mut ndspy_sys::PtDspyDevFormat,
-    flag_stuff: *mut ndspy_sys::PtFlagStuff,
+    _flag_stuff: *mut ndspy_sys::PtFlagStuff,
 ) -> ndspy_sys::PtDspyError {
     if (image_handle_ptr.is_null()) || (output_filename.is_null()) {
         return ndspy_sys::PtDspyError_PkDspyErrorBadParams;
@@ -146,11 +373,19 @@ pub extern "C" fn DspyImageOpen(
     let mut rgb_index = None;
     let mut albedo_index = None;
     let mut normal_index = None;
+    let mut channel_names = Vec::with_capacity(format.len());
+    let mut sample_formats = Vec::with_capacity(format.len());
 
     // This loops through each format (channel), r, g, b, a etc.
     format.iter_mut().enumerate().for_each(|format| {
-        // Ensure all channels are sent to us as 32bit float.
-        format.1.type_ = ndspy_sys::PkDspyFloat32;
+        // Take the format the renderer was asked for. If there is none
+        // or we do not support it the channel is sent to us as 32bit
+        // float.
+        let sample_format = SampleFormat::from_type(format.1.type_).unwrap_or_else(|| {
+            format.1.type_ = ndspy_sys::PkDspyFloat32;
+            SampleFormat::F32
+        });
+        sample_formats.push((sample_format, ByteOrder::from_type(format.1.type_)));
 
         // FIXME: add support for specifying AOV and detect type
         // for indexing (.r vs .x)
@@ -158,6 +393,8 @@ pub extern "C" fn DspyImageOpen(
 
         let i = format.0;
 
+        channel_names.push(name.to_string_lossy().into_owned());
+
         if "r" == name.to_string_lossy() {
             rgb_index = Some(i);
         } else if "a" == name.to_string_lossy() {
@@ -184,9 +421,32 @@ pub extern "C" fn DspyImageOpen(
     */
 
     if !output_filename.is_null() {
-        let image = Box::new(ImageData {
-            data: vec![0.0f32; (width * height * format_count) as _],
-            offset: 0,
+        // Used for expanding the file name.
+        let frame = get_parameter::<i32>("frame", b'i', 1, &parameter).or_else(|| {
+            get_parameter::<f32>("frame", b'f', 1, &parameter).map(|frame| frame.round() as _)
+        });
+        let camera = get_parameter::<*const std::os::raw::c_char>("camera", b's', 1, &parameter)
+            .map(|c_str_ptr| {
+                unsafe { CStr::from_ptr(c_str_ptr) }
+                    .to_string_lossy()
+                    .into_owned()
+            });
+
+        let mut image = Box::new(ImageData {
+            data: match get_parameter::<*const std::os::raw::c_char>(
+                "shared_memory",
+                b's',
+                1,
+                &parameter,
+            ) {
+                None => Framebuffer::Heap(vec![0.0f32; (width * height * format_count) as _]),
+                Some(c_str_ptr) => Framebuffer::shared(
+                    &unsafe { CStr::from_ptr(c_str_ptr) }.to_string_lossy(),
+                    width as _,
+                    height as _,
+                    &channel_names,
+                ),
+            },
 
             width: width as _,
             height: height as _,
@@ -200,6 +460,20 @@ pub extern "C" fn DspyImageOpen(
             far: get_parameter::<f32>("far", b'f', 1, &parameter),
 
             num_channels: format_count as _,
+            channel_names,
+            sample_formats,
+            packed: None,
+            layer_name: get_parameter::<*const std::os::raw::c_char>(
+                "layername",
+                b's',
+                1,
+                &parameter,
+            )
+            .map(|c_str_ptr| {
+                unsafe { CStr::from_ptr(c_str_ptr) }
+                    .to_string_lossy()
+                    .into_owned()
+            }),
             alpha_index,
             rgb_index,
             albedo_index,
@@ -215,11 +489,100 @@ pub extern "C" fn DspyImageOpen(
                         .unwrap()
                 }),
 
-            //clipping:
-            /*
-            screen_window_center: [f32; 2],
-            screen_window_width: f32,
-                */
+            // The renderer sends us the crop window (or the overscan
+            // region) only. Where this sits inside the full frame is
+            // told by the origin and the original size.
+            data_window: IntegerBounds::new(
+                get_parameter::<[i32; 2]>("origin", b'i', 2, &parameter)
+                    .map(|o| (o[0], o[1]))
+                    .unwrap_or((0, 0)),
+                (width as usize, height as usize),
+            ),
+            display_window: IntegerBounds::from_dimensions(
+                get_parameter::<[i32; 2]>("OriginalSize", b'i', 2, &parameter)
+                    .map(|s| (s[0] as usize, s[1] as usize))
+                    .unwrap_or((width as _, height as _)),
+            ),
+
+            color_space: get_parameter::<*const std::os::raw::c_char>(
+                "colorspace",
+                b's',
+                1,
+                &parameter,
+            )
+            .and_then(|c_str_ptr| {
+                let name = unsafe { CStr::from_ptr(c_str_ptr) }.to_string_lossy();
+                let color_space = ColorSpace::from_name(&name);
+                if color_space.is_none() {
+                    eprintln!("[r-display] selected colorspace is not supported; ignoring");
+                }
+                color_space
+            }),
+            white_luminance: get_parameter::<f32>("white_luminance", b'f', 1, &parameter),
+
+            exr_header: get_exr_header_attributes(&parameter),
+
+            comments: get_parameter::<*const std::os::raw::c_char>("comments", b's', 1, &parameter)
+                .map(|c_str_ptr| {
+                    unsafe { CStr::from_ptr(c_str_ptr) }
+                        .to_string_lossy()
+                        .into_owned()
+                }),
+            open_time: Instant::now(),
+            checkpoint_interval: get_parameter::<f32>("checkpoint_interval", b'f', 1, &parameter)
+                .filter(|&seconds| 0. < seconds)
+                .map(Duration::from_secs_f32),
+            last_checkpoint: Instant::now(),
+            checkpoint: None,
+            render_time: None,
+
+            // Left, right, bottom, top – as in RiScreenWindow.
+            screen_window: get_parameter::<[f32; 4]>("ScreenWindow", b'f', 4, &parameter),
+
+            focal_length: get_parameter::<f32>("focallength", b'f', 1, &parameter),
+            f_stop: get_parameter::<f32>("fstop", b'f', 1, &parameter),
+            focal_distance: get_parameter::<f32>("focaldistance", b'f', 1, &parameter),
+            // Open & close time.
+            shutter: get_parameter::<[f32; 2]>("shutter", b'f', 2, &parameter),
+            // Width & height of the film back/sensor, in millimeters.
+            film_back: get_parameter::<[f32; 2]>("filmback", b'f', 2, &parameter),
+
+            // An explicit envmap parameter wins over the projection
+            // the renderer tells us about.
+            environment_map: match get_parameter::<*const std::os::raw::c_char>(
+                "envmap", b's', 1, &parameter,
+            ) {
+                Some(c_str_ptr) => match unsafe { CStr::from_ptr(c_str_ptr) }
+                    .to_string_lossy()
+                    .to_ascii_lowercase()
+                    .as_str()
+                {
+                    "none" => None,
+                    "latlong" => Some(EnvironmentMap::LatitudeLongitude),
+                    "cube" => Some(EnvironmentMap::Cube),
+                    _ => {
+                        eprintln!("[r-display] selected envmap is not supported; ignoring");
+                        None
+                    }
+                },
+                None => {
+                    get_parameter::<*const std::os::raw::c_char>("projection", b's', 1, &parameter)
+                        .and_then(|c_str_ptr| {
+                            match unsafe { CStr::from_ptr(c_str_ptr) }
+                                .to_string_lossy()
+                                .to_ascii_lowercase()
+                                .as_str()
+                            {
+                                "spherical" | "sphericalcamera" | "latlong" | "equirectangular" => {
+                                    Some(EnvironmentMap::LatitudeLongitude)
+                                }
+                                "cube" | "cubemap" => Some(EnvironmentMap::Cube),
+                                _ => None,
+                            }
+                        })
+                }
+            },
+
             premultiply: match get_parameter::<u32>("premultiply", b'i', 1, &parameter) {
                 Some(b) => b != 0,
                 None => true,
@@ -274,6 +637,46 @@ pub extern "C" fn DspyImageOpen(
             tile_size: get_parameter::<[u32; 2]>("tile_size", b'i', 2, &parameter)
                 .map(|t| Vec2::from((t[0] as _, t[1] as _))),
 
+            levels: match get_parameter::<*const std::os::raw::c_char>(
+                "levels", b's', 1, &parameter,
+            ) {
+                None => None,
+                Some(c_str_ptr) => match unsafe { CStr::from_ptr(c_str_ptr) }
+                    .to_string_lossy()
+                    .to_ascii_lowercase()
+                    .as_str()
+                {
+                    "none" => None,
+                    "mipmap" => Some(LevelMode::MipMap),
+                    "ripmap" => Some(LevelMode::RipMap),
+                    _ => {
+                        eprintln!("[r-display] selected levels are not supported; ignoring");
+                        None
+                    }
+                },
+            },
+
+            level_rounding: match get_parameter::<*const std::os::raw::c_char>(
+                "level_rounding",
+                b's',
+                1,
+                &parameter,
+            ) {
+                None => RoundingMode::Down,
+                Some(c_str_ptr) => match unsafe { CStr::from_ptr(c_str_ptr) }
+                    .to_string_lossy()
+                    .to_ascii_lowercase()
+                    .as_str()
+                {
+                    "down" => RoundingMode::Down,
+                    "up" => RoundingMode::Up,
+                    _ => {
+                        eprintln!("[r-display] selected level_rounding is not supported; reverting to 'down'");
+                        RoundingMode::Down
+                    }
+                },
+            },
+
             file_name: unsafe {
                 CStr::from_ptr(output_filename)
                     .to_str()
@@ -281,6 +684,121 @@ pub extern "C" fn DspyImageOpen(
                     .to_string()
             },
 
+            format: match get_parameter::<*const std::os::raw::c_char>(
+                "format", b's', 1, &parameter,
+            ) {
+                // Chosen from the expanded file name below.
+                None => FileFormat::Exr,
+                Some(c_str_ptr) => {
+                    FileFormat::from_name(&unsafe { CStr::from_ptr(c_str_ptr) }.to_string_lossy())
+                        .unwrap_or_else(|| {
+                            eprintln!("[r-display] selected format is not supported; reverting to 'exr'");
+                            FileFormat::Exr
+                        })
+                }
+            },
+
+            view: get_parameter::<*const std::os::raw::c_char>("view", b's', 1, &parameter).map(
+                |c_str_ptr| {
+                    unsafe { CStr::from_ptr(c_str_ptr) }
+                        .to_string_lossy()
+                        .into_owned()
+                },
+            ),
+            file_index: 0,
+            manifest: match get_parameter::<u32>("manifest", b'i', 1, &parameter) {
+                Some(b) => b != 0,
+                None => false,
+            },
+            preview: match get_parameter::<u32>("preview", b'i', 1, &parameter) {
+                Some(b) => b != 0,
+                None => false,
+            },
+            extra_outputs: get_parameter::<*const std::os::raw::c_char>(
+                "extra_outputs",
+                b's',
+                1,
+                &parameter,
+            )
+            .map(|c_str_ptr| {
+                unsafe { CStr::from_ptr(c_str_ptr) }
+                    .to_string_lossy()
+                    .split(|c: char| ',' == c || c.is_whitespace())
+                    .filter(|name| !name.is_empty())
+                    .filter_map(|name| {
+                        extra_output::Format::from_name(name).or_else(|| {
+                            eprintln!(
+                                "[r-display] extra output format '{}' is not supported; ignoring",
+                                name
+                            );
+                            None
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+            quantize: get_parameter::<[f32; 4]>("quantize", b'f', 4, &parameter),
+            dither: get_parameter::<f32>("dither", b'f', 1, &parameter).unwrap_or(0.5),
+            view_transform: view_transform::ViewTransform {
+                exposure: get_parameter::<f32>("view_exposure", b'f', 1, &parameter)
+                    .unwrap_or(0.),
+                curve: match get_parameter::<*const std::os::raw::c_char>(
+                    "view_transform",
+                    b's',
+                    1,
+                    &parameter,
+                ) {
+                    None => view_transform::Curve::Srgb,
+                    Some(c_str_ptr) => view_transform::Curve::from_name(
+                        &unsafe { CStr::from_ptr(c_str_ptr) }.to_string_lossy(),
+                    )
+                    .unwrap_or_else(|| {
+                        eprintln!("[r-display] selected view_transform is not supported; reverting to 'srgb'");
+                        view_transform::Curve::Srgb
+                    }),
+                },
+                gamma: get_parameter::<f32>("view_gamma", b'f', 1, &parameter).unwrap_or(2.2),
+            },
+            tev: None,
+            stream: None,
+            progressive: match get_parameter::<u32>("progressive", b'i', 1, &parameter) {
+                Some(b) => b != 0,
+                None => false,
+            },
+            coverage: coverage::Coverage::new(width as _, height as _),
+
+            on_abort: match get_parameter::<*const std::os::raw::c_char>(
+                "on_abort",
+                b's',
+                1,
+                &parameter,
+            ) {
+                None => OnAbort::Mark,
+                Some(c_str_ptr) => {
+                    OnAbort::from_name(&unsafe { CStr::from_ptr(c_str_ptr) }.to_string_lossy())
+                        .unwrap_or_else(|| {
+                            eprintln!("[r-display] selected on_abort policy is not supported; reverting to 'mark'");
+                            OnAbort::Mark
+                        })
+                }
+            },
+
+            overwrite: match get_parameter::<*const std::os::raw::c_char>(
+                "overwrite",
+                b's',
+                1,
+                &parameter,
+            ) {
+                None => Overwrite::Always,
+                Some(c_str_ptr) => {
+                    Overwrite::from_name(&unsafe { CStr::from_ptr(c_str_ptr) }.to_string_lossy())
+                        .unwrap_or_else(|| {
+                            eprintln!("[r-display] selected overwrite policy is not supported; reverting to 'always'");
+                            Overwrite::Always
+                        })
+                }
+            },
+
             denoise: num::clamp(
                 get_parameter::<f32>("denoise", b'f', 1, &parameter).unwrap_or(1.),
                 0.,
@@ -290,18 +808,108 @@ pub extern "C" fn DspyImageOpen(
             //progress: AtomicU16::new(0),
             total_pixels: (width * height) as _,
             finished_pixels: 0,
+            progress: progress::Progress::new(
+                match get_parameter::<u32>("progress", b'i', 1, &parameter) {
+                    Some(b) => b != 0,
+                    None => false,
+                },
+                get_parameter::<*const std::os::raw::c_char>("progress_file", b's', 1, &parameter)
+                    .map(|c_str_ptr| {
+                        unsafe { CStr::from_ptr(c_str_ptr) }
+                            .to_string_lossy()
+                            .into_owned()
+                    }),
+                Duration::from_secs_f32(
+                    get_parameter::<f32>("progress_interval", b'f', 1, &parameter)
+                        .unwrap_or(10.)
+                        .max(0.),
+                ),
+            ),
         });
 
+        let tokens = [
+            ("layer", image.layer_name()),
+            ("frame", frame.map(|frame| format!("{:04}", frame))),
+            ("camera", camera),
+            ("view", image.view.clone()),
+            // YYYY-MM-DD
+            (
+                "date",
+                Some(provenance::capture_date().0[..10].replace(':', "-")),
+            ),
+        ]
+        .iter()
+        .filter_map(|(token, value)| Some((*token, value.clone()?)))
+        .collect::<Vec<_>>();
+
+        image.file_name = file_name::expand(&image.file_name, &tokens, frame);
+
+        // The extension may come from a token or environment variable.
+        if get_parameter::<*const std::os::raw::c_char>("format", b's', 1, parameter).is_none() {
+            image.format = FileFormat::from_file_name(&image.file_name);
+        }
+
+        // Narrow samples are only widened once the whole frame is
+        // needed. Checkpoints & shared memory need it all the time.
+        if image.checkpoint_interval.is_none() && !image.data.is_shared() {
+            image.packed =
+                sample_format::Packed::new(image.width, image.height, &image.sample_formats);
+            if image.packed.is_some() {
+                image.data = Framebuffer::Heap(Vec::new());
+            }
+        }
+
+        file_name::create_parent_directory(&image.file_name);
+
+        // Display instances writing to the same file (e.g. stereo views)
+        // share it.
+        image.file_index = registry::open(&image.file_name);
+
+        if let Some(address) = get_parameter::<*const std::os::raw::c_char>(
+            "tev", b's', 1, &parameter,
+        )
+        .map(|c_str_ptr| {
+            unsafe { CStr::from_ptr(c_str_ptr) }
+                .to_string_lossy()
+                .into_owned()
+        }) {
+            let address = if address.is_empty() {
+                tev::DEFAULT_ADDRESS
+            } else {
+                &address
+            };
+
+            // Display instances sharing a file get one image each.
+            let image_name = if 0 == image.file_index {
+                image.file_name.clone()
+            } else {
+                format!(
+                    "{} ({})",
+                    image.file_name,
+                    image
+                        .layer_name()
+                        .unwrap_or_else(|| image.file_index.to_string())
+                )
+            };
+
+            image.tev = tev::Tev::connect(
+                address,
+                &image_name,
+                image.width,
+                image.height,
+                &image.channel_names,
+                image
+                    .rgb_index
+                    .map(|rgb_index| (image.view_transform, rgb_index)),
+            );
+        }
+
         // Get raw pointer to heap-allocated ImageData struct and pass
         // ownership to image_handle_ptr.
         unsafe {
             *image_handle_ptr = Box::into_raw(image) as *mut _;
         }
 
-        unsafe {
-            (*flag_stuff).flags = ndspy_sys::PkDspyFlagsWantsScanLineOrder as _;
-        }
-
         ndspy_sys::PtDspyError_PkDspyErrorNone
     } else {
         // We're missing an output file name.
@@ -314,61 +922,103 @@ pub extern "C" fn DspyImageQuery(
     image_handle: ndspy_sys::PtDspyImageHandle,
     query_type: ndspy_sys::PtDspyQueryType,
     data_len: c_int,
-    mut data: *const c_void,
+    data: *mut c_void,
 ) -> ndspy_sys::PtDspyError {
-    if (data.is_null()) && (query_type != ndspy_sys::PtDspyQueryType_PkStopQuery) {
+    // The stop query is the only one that does not return anything.
+    if ndspy_sys::PtDspyQueryType_PkStopQuery == query_type {
+        return ndspy_sys::PtDspyError_PkDspyErrorNone;
+    }
+
+    if data.is_null() {
         return ndspy_sys::PtDspyError_PkDspyErrorBadParams;
     }
 
-    // Looks like this is actually needed for a minimal implementation
-    // as we never get called with the next two query types by 3Delight.
-    // But we leave this code be – just in case. :]
-    match query_type {
-        ndspy_sys::PtDspyQueryType_PkSizeQuery => {
-            let size_info = Box::new({
-                if image_handle.is_null() {
-                    ndspy_sys::PtDspySizeInfo {
-                        width: 1920,
-                        height: 1080,
-                        aspectRatio: 1.0,
-                    }
-                } else {
-                    let image = unsafe { Box::from_raw(image_handle as *mut ImageData) };
+    let image = unsafe { (image_handle as *mut ImageData).as_ref() };
 
-                    ndspy_sys::PtDspySizeInfo {
-                        width: image.width as _,
-                        height: image.height as _,
-                        aspectRatio: image.pixel_aspect,
-                    }
-                }
-            });
+    match query_type {
+        ndspy_sys::PtDspyQueryType_PkSizeQuery => answer_query(
+            match image {
+                Some(image) => ndspy_sys::PtDspySizeInfo {
+                    width: image.width as _,
+                    height: image.height as _,
+                    aspectRatio: image.pixel_aspect,
+                },
+                None => ndspy_sys::PtDspySizeInfo {
+                    width: 1920,
+                    height: 1080,
+                    aspectRatio: 1.0,
+                },
+            },
+            data,
+            data_len,
+        ),
 
-            debug_assert!(mem::size_of::<ndspy_sys::PtDspySizeInfo>() <= data_len as _);
+        ndspy_sys::PtDspyQueryType_PkOverwriteQuery => answer_query(
+            ndspy_sys::PtDspyOverwriteInfo {
+                overwrite: image.map_or(true, |image| Overwrite::Always == image.overwrite)
+                    as ndspy_sys::PtDspyUnsigned8,
+                unused: 0,
+            },
+            data,
+            data_len,
+        ),
+
+        // Only if asked to; progressive images can not be written while
+        // they render.
+        ndspy_sys::PtDspyQueryType_PkProgressiveQuery => answer_query(
+            ndspy_sys::PtDspyProgressiveInfo {
+                acceptProgressive: image.is_some_and(|image| image.progressive) as _,
+            },
+            data,
+            data_len,
+        ),
 
-            // Transfer ownership of the size_query heap object to the
-            // data pointer.
-            data = Box::into_raw(size_info) as *mut _;
-        }
+        // We keep all pixels so we never need them sent again.
+        ndspy_sys::PtDspyQueryType_PkRedrawQuery => answer_query(
+            ndspy_sys::PtDspyRedrawInfo { redraw: false as _ },
+            data,
+            data_len,
+        ),
 
-        ndspy_sys::PtDspyQueryType_PkOverwriteQuery => {
-            let overwrite_info = Box::new(ndspy_sys::PtDspyOverwriteInfo {
-                overwrite: true as ndspy_sys::PtDspyUnsigned8,
-                unused: 0,
-            });
+        ndspy_sys::PtDspyQueryType_PkRenderProgress => answer_query::<
+            ndspy_sys::PtDspyRenderProgressFuncPtr,
+        >(
+            Some(render_progress), data, data_len
+        ),
 
-            // Transfer ownership of the size_query heap object to the
-            // data pointer.
-            data = Box::into_raw(overwrite_info) as *mut _;
-        }
+        // This includes elapsed time, for which ndspy.h defines no query.
+        _ => ndspy_sys::PtDspyError_PkDspyErrorUnsupported,
+    }
+}
 
-        _ => {
-            return ndspy_sys::PtDspyError_PkDspyErrorUnsupported;
-        }
+/// Writes the answer to a query into the `data_len` bytes at `data`.
+fn answer_query<T>(answer: T, data: *mut c_void, data_len: c_int) -> ndspy_sys::PtDspyError {
+    if (data_len as usize) < mem::size_of::<T>() {
+        return ndspy_sys::PtDspyError_PkDspyErrorBadParams;
     }
 
+    unsafe { std::ptr::write_unaligned(data as *mut T, answer) };
+
     ndspy_sys::PtDspyError_PkDspyErrorNone
 }
 
+/// Called by the renderer with its overall progress, in percent.
+unsafe extern "C" fn render_progress(
+    image_handle: ndspy_sys::PtDspyImageHandle,
+    progress: f32,
+) -> ndspy_sys::PtDspyError {
+    match (image_handle as *mut ImageData).as_mut() {
+        Some(image) => {
+            image
+                .progress
+                .update_from_renderer(&image.file_name, progress);
+
+            ndspy_sys::PtDspyError_PkDspyErrorNone
+        }
+        None => ndspy_sys::PtDspyError_PkDspyErrorBadParams,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn DspyImageData(
     image_handle: ndspy_sys::PtDspyImageHandle,
@@ -376,8 +1026,8 @@ pub extern "C" fn DspyImageData(
     x_max_plus_one: c_int,
     y_min: c_int,
     y_max_plus_one: c_int,
-    _entry_size: c_int,
-    data: *const f32,
+    entry_size: c_int,
+    data: *const u8,
 ) -> ndspy_sys::PtDspyError {
     if image_handle.is_null() {
         return ndspy_sys::PtDspyError_PkDspyErrorBadParams;
@@ -385,24 +1035,116 @@ pub extern "C" fn DspyImageData(
 
     let mut image = unsafe { &mut *(image_handle as *mut ImageData) };
 
-    // Calculate progress 0..1000.
-    // We use this in the artisan render loop to
-    // report back to Ae.
-    image.finished_pixels += ((x_max_plus_one - x_min) * (y_max_plus_one - y_min)) as usize;
-    //eprintln!("[r-display] {}", (100 * image.finished_pixels) / image.total_pixels);
+    // Buckets may arrive in any order; make sure this one is inside the
+    // frame.
+    if x_min < 0
+        || y_min < 0
+        || x_max_plus_one <= x_min
+        || y_max_plus_one <= y_min
+        || image.width < x_max_plus_one as _
+        || image.height < y_max_plus_one as _
+    {
+        return ndspy_sys::PtDspyError_PkDspyErrorBadParams;
+    }
+
+    // Each pixel must hold a sample of every channel.
+    if data.is_null()
+        || entry_size <= 0
+        || (entry_size as usize) < sample_format::pixel_size(&image.sample_formats)
+    {
+        return ndspy_sys::PtDspyError_PkDspyErrorBadParams;
+    }
+
+    // All display instances have been opened once the first bucket
+    // arrives.
+    if 0 == image.finished_pixels && image.can_stream() {
+        if let Some(file_name) = image.overwrite.file_name(&image.file_name) {
+            eprintln!("[r-display] streaming EXR ...");
+            image.stream = Some(stream::Stream::start(image, file_name));
+            // Rows are kept by the stream until written.
+            image.data = Framebuffer::Heap(Vec::new());
+            image.packed = None;
+        }
+    }
 
-    let data_size =
-        (image.num_channels as i32 * (x_max_plus_one - x_min) * (y_max_plus_one - y_min)) as _;
+    image.finished_pixels += image.coverage.add(
+        x_min as _,
+        x_max_plus_one as _,
+        y_min as _,
+        y_max_plus_one as _,
+    );
+    image
+        .progress
+        .update(&image.file_name, image.finished_pixels, image.total_pixels);
+
+    let num_pixels = ((x_max_plus_one - x_min) * (y_max_plus_one - y_min)) as usize;
+    let pixels = unsafe { std::slice::from_raw_parts(data, entry_size as usize * num_pixels) };
+
+    let converted_data;
+    let data = if sample_format::is_native_f32(&image.sample_formats)
+        && entry_size as usize == image.num_channels * mem::size_of::<f32>()
+    {
+        unsafe { std::slice::from_raw_parts(data as *const f32, image.num_channels * num_pixels) }
+    } else {
+        converted_data = sample_format::to_f32(pixels, entry_size as _, &image.sample_formats);
+        &converted_data
+    };
 
-    unsafe {
-        ptr::copy_nonoverlapping(
+    if let Some(stream) = image.stream.as_ref() {
+        stream.push(
+            x_min as _,
+            y_min as _,
+            (x_max_plus_one - x_min) as _,
+            (y_max_plus_one - y_min) as _,
+            image.num_channels,
             data,
-            image.data.as_mut_ptr().offset(image.offset),
-            data_size,
         );
+    } else if let Some(packed) = image.packed.as_mut() {
+        packed.insert(
+            x_min as _,
+            y_min as _,
+            (x_max_plus_one - x_min) as _,
+            (y_max_plus_one - y_min) as _,
+            entry_size as _,
+            pixels,
+        );
+    } else {
+        // Copy the bucket row by row to where it belongs in the frame.
+        let row_len = image.num_channels * (x_max_plus_one - x_min) as usize;
+        data.chunks(row_len).enumerate().for_each(|(row, samples)| {
+            let start =
+                image.num_channels * (x_min as usize + (y_min as usize + row) * image.width);
+            image.data[start..start + row_len].copy_from_slice(samples);
+        });
+    }
+
+    // Only announce the pixels once they are in place.
+    image.data.set_finished_pixels(image.finished_pixels);
+
+    if let Some(tev) = image.tev.as_mut() {
+        if !tev.update(
+            x_min as _,
+            y_min as _,
+            (x_max_plus_one - x_min) as _,
+            (y_max_plus_one - y_min) as _,
+            data,
+        ) {
+            image.tev = None;
+        }
     }
 
-    image.offset += data_size as isize;
+    if let Some(checkpoint_interval) = image.checkpoint_interval {
+        // Skip checkpoints while the previous one is still being written.
+        let writing = image
+            .checkpoint
+            .as_ref()
+            .is_some_and(|checkpoint| !checkpoint.is_finished());
+
+        if checkpoint_interval <= image.last_checkpoint.elapsed() && !writing {
+            image.last_checkpoint = Instant::now();
+            image.checkpoint = checkpoint::start(image);
+        }
+    }
 
     ndspy_sys::PtDspyError_PkDspyErrorNone
 }
@@ -433,34 +1175,488 @@ fn add_field_of_views(layer_attributes: &mut LayerAttributes) {
     }
 }
 
-fn _debug_exr(file_name: &str, data: &[f32], dimensions: (usize, usize)) {
-    eprintln!("[r-display] writing {}", file_name);
-
-    let sample = |position: Vec2<usize>| {
-        let index = 3 * (position.x() + position.y() * dimensions.0);
-
-        Pixel::rgb(data[index], data[index + 1], data[index + 2])
-    };
+/// Adds the physical camera (lens) data.
+///
+/// Aperture, focus distance and exposure time have standard EXR
+/// attributes. The rest is stored in custom attributes named like their
+/// counterparts in recent versions of the OpenEXR standard.
+fn add_camera(image: &ImageData, layer_attributes: &mut LayerAttributes) {
+    layer_attributes.aperture = image.f_stop;
+    layer_attributes.focus = image.focal_distance;
+
+    if let Some(focal_length) = image.focal_length {
+        layer_attributes.other.insert(
+            Text::from("nominalFocalLength").unwrap(),
+            AttributeValue::F32(focal_length),
+        );
+    }
 
-    let image_info = ImageInfo::rgb(dimensions, SampleType::F32);
+    if let Some(shutter) = image.shutter {
+        layer_attributes.exposure = Some(shutter[1] - shutter[0]);
+        layer_attributes.other.insert(
+            Text::from("shutter").unwrap(),
+            AttributeValue::FloatVec2(Vec2(shutter[0], shutter[1])),
+        );
+    }
 
-    image_info
-        .write_pixels_to_file(
-            file_name,
-            // this will actually generate the pixels in parallel on all cores
-            write_options::high(),
-            &sample,
-        )
-        .unwrap();
+    if let Some(film_back) = image.film_back {
+        layer_attributes.other.insert(
+            Text::from("sensorOverallDimensions").unwrap(),
+            AttributeValue::FloatVec2(Vec2(film_back[0], film_back[1])),
+        );
+    }
 }
 
-fn write_exr(image: &ImageData) {
-    // -> Result<(), std::boxed::Box<dyn std::error::Error>> {
-    if let (Some(rgb_index), Some(alpha_index)) = (image.rgb_index, image.alpha_index) {
-        println!("[r-display] writing EXR ...");
+/// Adds when, where and by whom the image was rendered as well as how
+/// it was denoised.
+fn add_provenance(image: &ImageData, layer_attributes: &mut LayerAttributes) {
+    let (capture_date, utc_offset) = provenance::capture_date();
+    layer_attributes.capture_date = Text::from(capture_date);
+    layer_attributes.utc_offset = Some(utc_offset);
 
-        let sample = |position: Vec2<usize>| {
-            let index = image.num_channels * (position.x() + position.y() * image.width);
+    layer_attributes.owner = provenance::user_name().and_then(Text::from);
+
+    if let Some(host_name) = provenance::host_name().and_then(Text::from) {
+        layer_attributes.other.insert(
+            Text::from("hostName").unwrap(),
+            AttributeValue::Text(host_name),
+        );
+    }
+
+    if let Some(render_time) = image.render_time {
+        layer_attributes.other.insert(
+            Text::from("renderTime").unwrap(),
+            AttributeValue::F32(render_time.as_secs_f32()),
+        );
+    }
+
+    layer_attributes.other.insert(
+        Text::from("denoise").unwrap(),
+        AttributeValue::F32(image.denoise),
+    );
+
+    if f32::EPSILON < image.denoise {
+        let mut features = vec![Text::from("color").unwrap()];
+
+        if image.albedo_index.is_some() {
+            features.push(Text::from("albedo").unwrap());
+
+            // Normal can only be used if albedo is present.
+            if image.normal_index.is_some() {
+                features.push(Text::from("normal").unwrap());
+            }
+        }
+
+        layer_attributes.other.insert(
+            Text::from("denoiseFeatures").unwrap(),
+            AttributeValue::TextVector(features),
+        );
+    }
+}
+
+/// Adds the attributes given through `exrheader_` parameters. They
+/// replace ours of the same name.
+///
+/// Standard attributes must go into their resp. fields; the `exr` crate
+/// refuses to write them as custom ones. Those we have no field for, or
+/// that were given with the wrong type, are skipped.
+fn add_exr_header(image: &ImageData, layer_attributes: &mut LayerAttributes) {
+    for (name, value) in &image.exr_header {
+        let name_string = name.to_string();
+
+        if !exr::meta::header::standard_names::ALL.contains(&name_string.as_bytes()) {
+            layer_attributes.other.insert(name.clone(), value.clone());
+            continue;
+        }
+
+        let text = || match value {
+            AttributeValue::Text(text) => Some(text.clone()),
+            _ => None,
+        };
+        let float = || match value {
+            AttributeValue::F32(float) => Some(*float),
+            AttributeValue::I32(integer) => Some(*integer as f32),
+            _ => None,
+        };
+        let matrix = || match value {
+            AttributeValue::Matrix4x4(matrix) => Some(*matrix),
+            _ => None,
+        };
+
+        if match name_string.as_str() {
+            "owner" => text().map(|text| layer_attributes.owner = Some(text)),
+            "comments" => text().map(|text| layer_attributes.comments = Some(text)),
+            "capDate" => text().map(|text| layer_attributes.capture_date = Some(text)),
+            "software" => text().map(|text| layer_attributes.software_name = Some(text)),
+            "renderingTransform" => {
+                text().map(|text| layer_attributes.rendering_transform_name = Some(text))
+            }
+            "lookModTransform" => {
+                text().map(|text| layer_attributes.look_modification_transform_name = Some(text))
+            }
+            "wrapmodes" => text().map(|text| layer_attributes.wrap_mode_name = Some(text)),
+            "utcOffset" => float().map(|float| layer_attributes.utc_offset = Some(float)),
+            "longitude" => float().map(|float| layer_attributes.longitude = Some(float)),
+            "latitude" => float().map(|float| layer_attributes.latitude = Some(float)),
+            "altitude" => float().map(|float| layer_attributes.altitude = Some(float)),
+            "focus" => float().map(|float| layer_attributes.focus = Some(float)),
+            "expTime" => float().map(|float| layer_attributes.exposure = Some(float)),
+            "aperture" => float().map(|float| layer_attributes.aperture = Some(float)),
+            "isoSpeed" => float().map(|float| layer_attributes.iso_speed = Some(float)),
+            "xDensity" => float().map(|float| layer_attributes.horizontal_density = Some(float)),
+            "whiteLuminance" => float().map(|float| layer_attributes.white_luminance = Some(float)),
+            "near" => float().map(|float| layer_attributes.near_clip_plane = Some(float)),
+            "far" => float().map(|float| layer_attributes.far_clip_plane = Some(float)),
+            "worldToCamera" => {
+                matrix().map(|matrix| layer_attributes.world_to_camera = Some(matrix))
+            }
+            "worldToNDC" => {
+                matrix().map(|matrix| layer_attributes.world_to_normalized_device = Some(matrix))
+            }
+            _ => None,
+        }
+        .is_none()
+        {
+            eprintln!(
+                "[r-display] exrheader_{} can not be set this way; ignoring",
+                name
+            );
+        }
+    }
+}
+
+/// Derives the screen window from the camera projection.
+///
+/// The normalized device space the `world_to_normalized_device` matrix
+/// maps to spans -1..1 across the display window (see
+/// `add_field_of_views()`). The screen window is where this range lands
+/// on the projection plane, in units of `x/z` & `y/z` of camera space
+/// (or just `x` & `y` for orthographic cameras). This captures
+/// off-centre (shifted lens) and tiled camera projections.
+fn add_screen_window(layer_attributes: &mut LayerAttributes) {
+    if let (Some(world_to_camera), Some(world_to_ndc)) = (
+        layer_attributes.world_to_camera,
+        layer_attributes.world_to_normalized_device,
+    ) {
+        let world_to_camera: &cgmath::Matrix4<f32> = (&world_to_camera).into();
+        let world_to_ndc: &cgmath::Matrix4<f32> = (&world_to_ndc).into();
+
+        let camera_to_ndc = match world_to_camera.invert() {
+            Some(camera_to_world) => world_to_ndc * camera_to_world,
+            None => return,
+        };
+
+        let project = |x: f32, y: f32| {
+            let p = camera_to_ndc * cgmath::Vector4::<f32>::new(x, y, 1., 1.);
+            cgmath::Vector2::<f32>::new(p.x / p.w, p.y / p.w)
+        };
+
+        // The projection is affine in x/z & y/z on the z = 1 plane.
+        let origin = project(0., 0.);
+        let scale =
+            cgmath::Vector2::<f32>::new(project(1., 0.).x - origin.x, project(0., 1.).y - origin.y);
+
+        if 0. == scale.x || 0. == scale.y || !scale.x.is_finite() || !scale.y.is_finite() {
+            return;
+        }
+
+        layer_attributes.screen_window_center = Vec2(-origin.x / scale.x, -origin.y / scale.y);
+        layer_attributes.screen_window_width = 2. / scale.x.abs();
+    }
+}
+
+fn _debug_exr(file_name: &str, data: &[f32], dimensions: (usize, usize)) {
+    eprintln!("[r-display] writing {}", file_name);
+
+    let sample = |position: Vec2<usize>| {
+        let index = 3 * (position.x() + position.y() * dimensions.0);
+
+        Pixel::rgb(data[index], data[index + 1], data[index + 2])
+    };
+
+    let image_info = ImageInfo::rgb(dimensions, SampleType::F32);
+
+    image_info
+        .write_pixels_to_file(
+            file_name,
+            // this will actually generate the pixels in parallel on all cores
+            write_options::high(),
+            &sample,
+        )
+        .unwrap();
+}
+
+/// Sets up the attributes and the encoding of the EXR for `image`.
+fn image_info(image: &ImageData) -> ImageInfo {
+    let mut image_info = ImageInfo::rgba((image.width, image.height), SampleType::F32);
+
+    image_info.image_attributes.pixel_aspect = image.pixel_aspect;
+
+    image_info.image_attributes.display_window = image.display_window;
+    image_info.layer_attributes.layer_position = image.data_window.position;
+
+    if let Some(color_space) = image.color_space {
+        image_info.image_attributes.chromaticities = Some(color_space.chromaticities());
+        image_info.layer_attributes.adopted_neutral = Some(color_space.adopted_neutral());
+    }
+    image_info.layer_attributes.white_luminance = image.white_luminance;
+
+    image_info.layer_attributes.view_name = image.view.as_ref().and_then(Text::from);
+
+    if let Some(comments) = &image.comments {
+        image_info.layer_attributes.comments = Text::from(comments);
+    }
+
+    image_info.layer_attributes.world_to_camera = image.world_to_camera;
+    image_info.layer_attributes.world_to_normalized_device = image.world_to_screen;
+
+    image_info.layer_attributes.near_clip_plane = image.near;
+    image_info.layer_attributes.far_clip_plane = image.far;
+
+    if let Some(renderer) = &image.renderer {
+        image_info.layer_attributes.software_name = exr::meta::attribute::Text::from(renderer);
+    }
+
+    add_camera(image, &mut image_info.layer_attributes);
+
+    add_provenance(image, &mut image_info.layer_attributes);
+
+    if image.preview {
+        image_info.layer_attributes.preview = view_transform::preview(image);
+    }
+
+    if image.is_incomplete() {
+        image_info.layer_attributes.other.insert(
+            Text::from("renderIncomplete").unwrap(),
+            AttributeValue::I32(1),
+        );
+    }
+
+    add_exr_header(image, &mut image_info.layer_attributes);
+
+    if image.environment_map.is_some() {
+        // A perspective projection makes no sense for environment
+        // maps.
+        image_info.layer_attributes.environment_map = image.environment_map;
+        image_info.layer_attributes.world_to_normalized_device = None;
+    } else {
+        add_field_of_views(&mut image_info.layer_attributes);
+
+        if let Some(screen_window) = image.screen_window {
+            image_info.layer_attributes.screen_window_center = Vec2(
+                0.5 * (screen_window[0] + screen_window[1]),
+                0.5 * (screen_window[2] + screen_window[3]),
+            );
+            image_info.layer_attributes.screen_window_width = screen_window[1] - screen_window[0];
+        } else {
+            add_screen_window(&mut image_info.layer_attributes);
+        }
+    }
+
+    let mut encoding = Encoding::for_compression(image.compression);
+
+    if let Some(l) = image.line_order {
+        encoding.line_order = l;
+    }
+
+    encoding.tile_size = image.tile_size;
+
+    // The encodings `exr` picks for RLE, PIZ, PXR24 & co. leave the line
+    // order unspecified for tiles. Without the tiles they came with this
+    // is invalid and the image would not be written.
+    if encoding.tile_size.is_none() && image.levels.is_none() {
+        if let LineOrder::Unspecified = encoding.line_order {
+            encoding.line_order = LineOrder::Increasing;
+        }
+    }
+
+    image_info.with_encoding(encoding)
+}
+
+/// The names the channels of `image` are written with, their index in
+/// a pixel & whether to quantize them linearly.
+///
+/// If the image has RGBA channels only these are returned. Otherwise
+/// all channels are.
+fn channel_layout(image: &ImageData) -> Vec<(String, usize, bool)> {
+    match (image.rgb_index, image.alpha_index) {
+        (Some(rgb_index), Some(alpha_index)) => vec![
+            ("A".to_string(), alpha_index, true),
+            ("B".to_string(), rgb_index + 2, false),
+            ("G".to_string(), rgb_index + 1, false),
+            ("R".to_string(), rgb_index, false),
+        ],
+        _ => {
+            // Strip the layer name and index from e.g. `albedo.000.r`
+            // unless this makes names clash.
+            let short_names: Vec<String> = image
+                .channel_names
+                .iter()
+                .map(|name| {
+                    let component = name.rsplit('.').next().unwrap_or(name);
+                    if 1 == component.len() {
+                        component.to_ascii_uppercase()
+                    } else {
+                        component.to_string()
+                    }
+                })
+                .collect();
+
+            let mut unique_names = short_names.clone();
+            unique_names.sort();
+            unique_names.dedup();
+
+            let names = if unique_names.len() == short_names.len() {
+                short_names
+            } else {
+                image.channel_names.clone()
+            };
+
+            names
+                .into_iter()
+                .enumerate()
+                .map(|(index, name)| (name, index, false))
+                .collect()
+        }
+    }
+}
+
+/// Returns the channels of `image`, including all resolution levels,
+/// for writing through the `full` API of the `exr` crate.
+///
+/// The channel names are prefixed with `prefix`.
+fn channels(image: &ImageData, prefix: &str) -> Vec<full::Channel> {
+    let mut channels: Vec<full::Channel> = channel_layout(image)
+        .into_iter()
+        .map(|(name, index, quantize_linearly)| {
+            let samples: Vec<f32> = image
+                .data
+                .par_chunks(image.num_channels)
+                .map(|chunk| chunk[index])
+                .collect();
+
+            full::Channel {
+                name: Text::from(format!("{}{}", prefix, name)).unwrap(),
+                content: full::ChannelData::F32(full::SampleMaps::Flat(levels::levels(
+                    samples,
+                    Vec2(image.width, image.height),
+                    image.levels.unwrap_or(LevelMode::Singular),
+                    image.level_rounding,
+                ))),
+                quantize_linearly,
+                sampling: Vec2(1, 1),
+            }
+        })
+        .collect();
+
+    // Channels must be sorted alphabetically.
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    channels
+}
+
+/// Describes how the pixels of `image` are divided into blocks.
+fn blocks(image: &ImageData, encoding: &Encoding) -> Blocks {
+    match (image.levels, encoding.tile_size) {
+        (None, None) => Blocks::ScanLines,
+        (None, Some(tile_size)) => Blocks::Tiles(TileDescription {
+            tile_size,
+            level_mode: LevelMode::Singular,
+            rounding_mode: RoundingMode::Down,
+        }),
+        // Levels can only be stored in tiled images.
+        (Some(level_mode), tile_size) => Blocks::Tiles(TileDescription {
+            tile_size: tile_size.unwrap_or(Vec2(64, 64)),
+            level_mode,
+            rounding_mode: image.level_rounding,
+        }),
+    }
+}
+
+/// Writes a tiled EXR that also contains the downsampled resolution
+/// levels of the image.
+///
+/// The `rgba` API of the `exr` crate can not store levels so we go
+/// through the `full` API and use the attributes and encoding that were
+/// already set up on `image_info`.
+fn write_exr_levels(image: &ImageData, image_info: ImageInfo, file_name: &str) -> UnitResult {
+    eprintln!(
+        "[r-display] computing {:?} levels ...",
+        image.levels.unwrap()
+    );
+
+    let layer = full::Layer {
+        channels: channels(image, "").into_iter().collect(),
+        blocks: blocks(image, &image_info.encoding),
+        attributes: image_info.layer_attributes,
+        size: image_info.resolution,
+        line_order: image_info.encoding.line_order,
+        compression: image_info.encoding.compression,
+    };
+
+    full::Image {
+        layers: std::iter::once(layer).collect(),
+        attributes: image_info.image_attributes,
+    }
+    .write_to_file(file_name, write_options::high())
+}
+
+/// Writes the images of all `views` into a single multi-view EXR.
+///
+/// The first view is the default view. Its channels are named as usual.
+/// The channels of all other views are prefixed with the view name, e.g.
+/// `right.R`.
+fn write_multi_view_exr(views: &[ImageData], file_name: &str) -> UnitResult {
+    let hero = &views[0];
+
+    println!("[r-display] writing multi-view EXR ...");
+
+    let image_info = image_info(hero);
+
+    let mut channels: Vec<full::Channel> = views
+        .iter()
+        .enumerate()
+        .flat_map(|(i, view)| {
+            if 0 == i {
+                channels(view, "")
+            } else {
+                channels(view, &format!("{}.", view.view.as_ref().unwrap()))
+            }
+        })
+        .collect();
+
+    // Channels must be sorted alphabetically.
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut layer_attributes = image_info.layer_attributes;
+    layer_attributes.view_name = None;
+    layer_attributes.multi_view_names = views
+        .iter()
+        .map(|view| Text::from(view.view.as_ref().unwrap()))
+        .collect();
+
+    let layer = full::Layer {
+        channels: channels.into_iter().collect(),
+        blocks: blocks(hero, &image_info.encoding),
+        attributes: layer_attributes,
+        size: image_info.resolution,
+        line_order: image_info.encoding.line_order,
+        compression: image_info.encoding.compression,
+    };
+
+    full::Image {
+        layers: std::iter::once(layer).collect(),
+        attributes: image_info.image_attributes,
+    }
+    .write_to_file(file_name, write_options::high())
+}
+
+fn write_exr(image: &ImageData, file_name: &str) -> UnitResult {
+    if let (Some(rgb_index), Some(alpha_index)) = (image.rgb_index, image.alpha_index) {
+        println!("[r-display] writing EXR ...");
+
+        let sample = |position: Vec2<usize>| {
+            let index = image.num_channels * (position.x() + position.y() * image.width);
 
             Pixel::rgba(
                 image.data[index + rgb_index],
@@ -470,45 +1666,215 @@ fn write_exr(image: &ImageData) {
             )
         };
 
-        let mut image_info = ImageInfo::rgba((image.width, image.height), SampleType::F32);
+        let image_info = image_info(image);
+
+        if image.levels.is_some() {
+            write_exr_levels(image, image_info, file_name)
+        } else {
+            // write it to a file with all cores in parallel
+            image_info
+                //.remove_excess()
+                .write_pixels_to_file(
+                    file_name,
+                    // this will actually generate the pixels in parallel on all cores
+                    write_options::high(),
+                    &sample,
+                )
+        }
+    } else {
+        println!("[r-display] Not writing EXR – missing rgb and/or alpha data");
+        Ok(())
+    }
+}
+
+/// Writes the RGB channels of `image` with `write`, for formats
+/// without alpha or layers.
+fn write_rgb(
+    image: &ImageData,
+    file_name: &str,
+    write: fn(&str, usize, usize, &[f32]) -> std::io::Result<()>,
+) -> UnitResult {
+    if let Some(rgb_index) = image.rgb_index {
+        println!("[r-display] writing {} ...", image.format.name());
+
+        let rgb = image
+            .data
+            .chunks(image.num_channels)
+            .flat_map(|pixel| pixel[rgb_index..rgb_index + 3].iter().copied())
+            .collect::<Vec<_>>();
+
+        Ok(write(file_name, image.width, image.height, &rgb)?)
+    } else {
+        println!(
+            "[r-display] Not writing {} – missing rgb data",
+            image.format.name()
+        );
+        Ok(())
+    }
+}
 
-        image_info.image_attributes.pixel_aspect = image.pixel_aspect;
+/// Writes `image` in its format.
+fn write_image(image: &ImageData, file_name: &str) -> UnitResult {
+    match image.format {
+        FileFormat::Exr => write_exr(image, file_name),
+        FileFormat::Hdr => write_rgb(image, file_name, hdr::write),
+        FileFormat::Pfm => write_rgb(image, file_name, pfm::write),
+    }
+}
 
-        //image_info.layer_attributes.comments = image.renderer;
+/// Writes the images of all display instances that target the same file
+/// into a single multi-layer EXR. Each instance becomes one layer.
+fn write_multi_layer_exr(images: &[ImageData], file_name: &str) -> UnitResult {
+    println!("[r-display] writing multi-layer EXR ...");
 
-        image_info.layer_attributes.world_to_camera = image.world_to_camera;
-        image_info.layer_attributes.world_to_normalized_device = image.world_to_screen;
+    let mut image_attributes = None;
+    let mut layer_names = Vec::<String>::new();
 
-        image_info.layer_attributes.near_clip_plane = image.near;
-        image_info.layer_attributes.far_clip_plane = image.far;
+    let layers = images
+        .iter()
+        .enumerate()
+        .map(|(i, image)| {
+            let image_info = image_info(image);
+
+            // Attributes shared by all layers are taken from the first
+            // one.
+            if image_attributes.is_none() {
+                image_attributes = Some(image_info.image_attributes.clone());
+            }
+
+            let mut layer_name = image.layer_name().unwrap_or_else(|| format!("layer{}", i));
 
-        if let Some(renderer) = &image.renderer {
-            image_info.layer_attributes.software_name = exr::meta::attribute::Text::from(renderer);
+            // Layers need unique names.
+            if layer_names.contains(&layer_name) {
+                layer_name = format!("{}{}", layer_name, i);
+            }
+            layer_names.push(layer_name.clone());
+
+            let mut layer_attributes = image_info.layer_attributes;
+            layer_attributes.layer_name = Text::from(layer_name);
+
+            full::Layer {
+                channels: channels(image, "").into_iter().collect(),
+                blocks: blocks(image, &image_info.encoding),
+                attributes: layer_attributes,
+                size: image_info.resolution,
+                line_order: image_info.encoding.line_order,
+                compression: image_info.encoding.compression,
+            }
+        })
+        .collect();
+
+    full::Image {
+        layers,
+        attributes: image_attributes.unwrap(),
+    }
+    .write_to_file(file_name, write_options::high())
+}
+
+/// Writes the images of all display instances that targeted the same
+/// file.
+fn write(mut images: Vec<ImageData>) {
+    // Keep the order the displays were opened in.
+    images.sort_by_key(|image| image.file_index);
+
+    let is_multi_view = 1 < images.len()
+        && images.iter().all(|image| {
+            image.view.is_some()
+                && image.rgb_index.is_some()
+                && image.alpha_index.is_some()
+                && (image.width, image.height) == (images[0].width, images[0].height)
+        });
+
+    // Display instances sharing a file are written together or not at
+    // all.
+    if images
+        .iter()
+        .any(|image| image.is_incomplete() && OnAbort::Discard == image.on_abort)
+    {
+        eprintln!("[r-display] discarding incomplete {}", images[0].file_name);
+        return;
+    }
+
+    let file_name = match images[0].overwrite.file_name(&images[0].file_name) {
+        Some(file_name) => file_name,
+        None => {
+            eprintln!(
+                "[r-display] {} exists; not overwriting it",
+                images[0].file_name
+            );
+            return;
         }
+    };
 
-        add_field_of_views(&mut image_info.layer_attributes);
+    let written = write_to_file(&file_name, |file_name| {
+        if FileFormat::Exr != images[0].format {
+            // Only EXRs have layers; write the beauty.
+            write_image(
+                images
+                    .iter()
+                    .find(|image| image.rgb_index.is_some())
+                    .unwrap_or(&images[0]),
+                file_name,
+            )
+        } else if is_multi_view {
+            write_multi_view_exr(&images, file_name)
+        } else if 1 < images.len() {
+            write_multi_layer_exr(&images, file_name)
+        } else {
+            write_image(&images[0], file_name)
+        }
+    });
 
-        let mut encoding = Encoding::for_compression(image.compression);
+    if written {
+        // The final image supersedes any checkpoints.
+        images
+            .iter()
+            .filter(|image| image.checkpoint_interval.is_some())
+            .for_each(|image| {
+                std::fs::remove_file(image.checkpoint_file_name()).ok();
+            });
 
-        if let Some(l) = image.line_order {
-            encoding.line_order = l;
+        if images[0].manifest {
+            manifest::write(&images, &file_name);
         }
 
-        encoding.tile_size = image.tile_size;
+        // Of the beauty layer.
+        if let Some(image) = images
+            .iter()
+            .find(|image| image.rgb_index.is_some() && image.alpha_index.is_some())
+        {
+            if !image.extra_outputs.is_empty() {
+                extra_output::write(image, &file_name);
+            }
+        }
+    }
+}
 
-        // write it to a file with all cores in parallel
-        image_info
-            .with_encoding(encoding)
-            //.remove_excess()
-            .write_pixels_to_file(
-                image.file_name.clone(),
-                // this will actually generate the pixels in parallel on all cores
-                write_options::high(),
-                &sample,
-            )
-            .unwrap();
-    } else {
-        println!("[r-display] Not writing EXR – missing rgb and/or alpha data");
+/// Calls `write` with a temporary file name and renames the result to
+/// `file_name` once done. A crash while writing then never leaves a
+/// truncated image at `file_name`.
+///
+/// Returns `true` if `file_name` was written.
+fn write_to_file(file_name: &str, write: impl FnOnce(&str) -> UnitResult) -> bool {
+    let temporary_file_name = file_name::temporary(file_name);
+
+    let result = write(&temporary_file_name).and_then(|_| {
+        // Nothing may have been written, e.g. if there was no RGBA data.
+        if std::path::Path::new(&temporary_file_name).exists() {
+            std::fs::rename(&temporary_file_name, file_name)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    });
+
+    match result {
+        Ok(written) => written,
+        Err(e) => {
+            eprintln!("[r-display] error writing {}: {}", file_name, e);
+            std::fs::remove_file(&temporary_file_name).ok();
+            false
+        }
     }
 }
 
@@ -516,7 +1882,30 @@ fn write_exr(image: &ImageData) {
 pub extern "C" fn DspyImageClose(
     image_handle: ndspy_sys::PtDspyImageHandle,
 ) -> ndspy_sys::PtDspyError {
-    let image = unsafe { &mut Box::from_raw(image_handle as *mut ImageData) };
+    let mut image = unsafe { Box::from_raw(image_handle as *mut ImageData) };
+
+    // Denoising and writing the image is not part of the render time.
+    image.render_time = Some(image.open_time.elapsed());
+
+    // The final image must not be overwritten by a late checkpoint.
+    if let Some(checkpoint) = image.checkpoint.take() {
+        checkpoint.join().ok();
+    }
+
+    if let Some(packed) = image.packed.take() {
+        image.data = Framebuffer::Heap(packed.to_f32());
+    }
+
+    if image.is_incomplete() {
+        eprintln!(
+            "[r-display] render stopped with {} of {} pixels; applying on_abort policy '{:?}'",
+            image.finished_pixels, image.total_pixels, image.on_abort
+        );
+
+        if OnAbort::Raw == image.on_abort {
+            image.denoise = 0.;
+        }
+    }
 
     let mut albedo = Vec::<f32>::new();
     let mut normal = Vec::<f32>::new();
@@ -632,13 +2021,765 @@ pub extern "C" fn DspyImageClose(
             if image.premultiply {
                 image.premultiply();
             }
+        } else if !image.premultiply {
+            image.unpremultiply();
         }
-    } else if !image.premultiply {
-        image.unpremultiply();
     }
 
-    write_exr(&image);
+    // Replace the preview with the denoised image.
+    if let Some(mut tev) = image.tev.take() {
+        tev.update_image(image.width, &image.data);
+    }
+
+    if let Some(stream) = image.stream.take() {
+        // The image was written while it rendered; we only need to
+        // wait for the last blocks.
+        if image.is_incomplete() && OnAbort::Discard == image.on_abort {
+            stream.discard();
+        } else {
+            stream.finish(&image);
+        }
+        registry::close(*image);
+    }
+    // If this was the last display instance writing to this file we get
+    // the images of all instances back.
+    else if let Some(images) = registry::close(*image) {
+        write(images);
+    }
 
-    // image goes out of scope – this will free the memory.
+    // images go out of scope – this will free the memory.
     ndspy_sys::PtDspyError_PkDspyErrorNone
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_util::*, DspyImageQuery};
+    use exr::{
+        meta::attribute::EnvironmentMap,
+        prelude::{rgba_image::*, simple_image},
+    };
+
+    #[test]
+    fn exr_header_attributes() {
+        let file_name = file_name("exr_header.exr");
+        render(
+            &file_name,
+            4,
+            4,
+            &[
+                ("exrheader_owner", Value::String(&["jane"])),
+                ("exrheader_isoSpeed", Value::Int(&[400])),
+                ("exrheader_shot", Value::String(&["sh010"])),
+                // Reserved and not settable.
+                ("exrheader_envmap", Value::String(&["latlong"])),
+                ("exrheader_dataWindow", Value::Int(&[0, 0])),
+            ],
+            &rgba(4, 4, |_, _| [1., 1., 1., 1.]),
+        );
+
+        let image = read_exr(&file_name);
+        let attributes = &image.layers[0].attributes;
+        assert_eq!(Text::from("jane"), attributes.owner);
+        assert_eq!(Some(400.), attributes.iso_speed);
+        assert_eq!(
+            Some(&AttributeValue::Text(Text::from("sh010").unwrap())),
+            attributes.other.get(&Text::from("shot").unwrap())
+        );
+        assert_eq!(None, attributes.environment_map);
+    }
+
+    fn is_marked_incomplete(image: &simple_image::Image) -> bool {
+        Some(&AttributeValue::I32(1))
+            == image.layers[0]
+                .attributes
+                .other
+                .get(&Text::from("renderIncomplete").unwrap())
+    }
+
+    fn accepts_progressive(display: &Display) -> bool {
+        let mut answer = ndspy_sys::PtDspyProgressiveInfo {
+            acceptProgressive: 2,
+        };
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            display.query(ndspy_sys::PtDspyQueryType_PkProgressiveQuery, &mut answer)
+        );
+        1 == answer.acceptProgressive
+    }
+
+    #[test]
+    fn size_query() {
+        let display = Display::open(&file_name("size.exr"), 16, 8, &RGBA, &[]).unwrap();
+
+        let mut answer = ndspy_sys::PtDspySizeInfo {
+            width: 0,
+            height: 0,
+            aspectRatio: 0.,
+        };
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            display.query(ndspy_sys::PtDspyQueryType_PkSizeQuery, &mut answer)
+        );
+        assert_eq!(
+            (16, 8, 1.),
+            (answer.width, answer.height, answer.aspectRatio)
+        );
+
+        // Too small for the answer.
+        let mut answer = 0u8;
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorBadParams,
+            display.query(ndspy_sys::PtDspyQueryType_PkSizeQuery, &mut answer)
+        );
+        display.close();
+    }
+
+    #[test]
+    fn other_queries() {
+        let display = Display::open(
+            &file_name("queries.exr"),
+            4,
+            4,
+            &RGBA,
+            &[("overwrite", Value::String(&["never"]))],
+        )
+        .unwrap();
+
+        let mut overwrite = ndspy_sys::PtDspyOverwriteInfo {
+            overwrite: 2,
+            unused: 0,
+        };
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            display.query(ndspy_sys::PtDspyQueryType_PkOverwriteQuery, &mut overwrite)
+        );
+        assert_eq!(0, overwrite.overwrite);
+
+        let mut redraw = ndspy_sys::PtDspyRedrawInfo { redraw: 2 };
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            display.query(ndspy_sys::PtDspyQueryType_PkRedrawQuery, &mut redraw)
+        );
+        assert_eq!(0, redraw.redraw);
+
+        // Answers nothing, not even to a null pointer.
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            DspyImageQuery(
+                display.handle,
+                ndspy_sys::PtDspyQueryType_PkStopQuery,
+                0,
+                std::ptr::null_mut()
+            )
+        );
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorBadParams,
+            DspyImageQuery(
+                display.handle,
+                ndspy_sys::PtDspyQueryType_PkSizeQuery,
+                0,
+                std::ptr::null_mut()
+            )
+        );
+        let mut answer = [0u8; 64];
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorUnsupported,
+            display.query(ndspy_sys::PtDspyQueryType_PkBucketVersion, &mut answer)
+        );
+        display.close();
+
+        // Before an image is opened.
+        let mut size = ndspy_sys::PtDspySizeInfo {
+            width: 0,
+            height: 0,
+            aspectRatio: 0.,
+        };
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            DspyImageQuery(
+                std::ptr::null_mut(),
+                ndspy_sys::PtDspyQueryType_PkSizeQuery,
+                std::mem::size_of_val(&size) as _,
+                &mut size as *mut _ as *mut _
+            )
+        );
+        assert_eq!((1920, 1080), (size.width, size.height));
+    }
+
+    #[test]
+    fn progressive_is_opt_in() {
+        let display = Display::open(&file_name("not_progressive.exr"), 4, 4, &RGBA, &[]).unwrap();
+        assert!(!accepts_progressive(&display));
+        display.close();
+
+        let display = Display::open(
+            &file_name("progressive.exr"),
+            4,
+            4,
+            &RGBA,
+            &[("progressive", Value::Int(&[1]))],
+        )
+        .unwrap();
+        assert!(accepts_progressive(&display));
+        display.close();
+    }
+
+    #[test]
+    fn progressive_passes_count_each_pixel_once() {
+        let progressive = [("progressive", Value::Int(&[1]))];
+        let first_pass = rgba(8, 8, |_, _| [0.5, 0.5, 0.5, 1.]);
+        let second_pass = rgba(8, 8, |_, _| [1., 1., 1., 1.]);
+
+        // Two passes over the top half are not a whole frame.
+        let file_name = file_name("progressive_incomplete.exr");
+        let display = Display::open(&file_name, 8, 8, &RGBA, &progressive).unwrap();
+        display.send(&first_pass[..4 * 8 * 4], 4);
+        display.send(&second_pass[..4 * 8 * 4], 4);
+        display.close();
+        assert!(is_marked_incomplete(&read_exr(&file_name)));
+
+        // A second pass stopped half way still leaves the frame
+        // complete.
+        let file_name = crate::test_util::file_name("progressive_complete.exr");
+        let display = Display::open(&file_name, 8, 8, &RGBA, &progressive).unwrap();
+        display.send(&first_pass, 4);
+        display.send(&second_pass[..4 * 8 * 4], 4);
+        display.close();
+
+        let image = read_exr(&file_name);
+        assert!(!is_marked_incomplete(&image));
+        let red = channel(&image, "R");
+        assert_eq!((1., 0.5), (red[0], red[63]));
+    }
+
+    #[test]
+    fn streamed_and_buffered_alpha_match() {
+        let samples = rgba(8, 8, |x, y| {
+            let alpha = (x + y) as f32 / 14.;
+            [0.5 * alpha, 0.25 * alpha, alpha, alpha]
+        });
+
+        for premultiply in [0, 1] {
+            let streamed = file_name("streamed.exr");
+            render(
+                &streamed,
+                8,
+                8,
+                &[("premultiply", Value::Int(&[premultiply]))],
+                &samples,
+            );
+
+            // Checkpoints need the whole frame.
+            let buffered = file_name("buffered.exr");
+            render(
+                &buffered,
+                8,
+                8,
+                &[
+                    ("premultiply", Value::Int(&[premultiply])),
+                    ("checkpoint_interval", Value::Float(&[1000.])),
+                ],
+                &samples,
+            );
+
+            let (streamed, buffered) = (read_exr(&streamed), read_exr(&buffered));
+            for name in ["R", "G", "B", "A"] {
+                assert_eq!(channel(&streamed, name), channel(&buffered, name));
+            }
+
+            let expected_red = if 0 == premultiply { 0.5 } else { 0.25 };
+            assert_eq!(expected_red, channel(&buffered, "R")[7]);
+        }
+    }
+
+    #[test]
+    fn untiled_compressions() {
+        let samples = rgba(4, 4, |x, y| [x as f32, y as f32, 0.5, 1.]);
+
+        for (name, compression) in [
+            ("none", Compression::Uncompressed),
+            ("rle", Compression::RLE),
+            ("zip", Compression::ZIP16),
+            ("piz", Compression::PIZ),
+            ("pxr24", Compression::PXR24),
+        ] {
+            // Streamed & buffered.
+            for checkpoint_interval in [0., 1000.] {
+                let file_name = file_name("untiled.exr");
+                render(
+                    &file_name,
+                    4,
+                    4,
+                    &[
+                        ("compression", Value::String(&[name])),
+                        ("checkpoint_interval", Value::Float(&[checkpoint_interval])),
+                    ],
+                    &samples,
+                );
+
+                let image = read_exr(&file_name);
+                assert_eq!(compression, image.layers[0].compression);
+                assert_eq!(LineOrder::Increasing, image.layers[0].line_order);
+                assert_eq!(3., channel(&image, "R")[3]);
+            }
+        }
+    }
+
+    #[test]
+    fn entry_size_is_checked() {
+        let display = Display::open(&file_name("entry_size.exr"), 2, 2, &RGBA, &[]).unwrap();
+        let bytes = [0u8; 4 * 16];
+
+        for entry_size in [0, 15] {
+            assert_eq!(
+                ndspy_sys::PtDspyError_PkDspyErrorBadParams,
+                display.data_bytes(0, 0, 2, 2, entry_size, &bytes)
+            );
+        }
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            display.data_bytes(0, 0, 2, 2, 16, &bytes)
+        );
+        display.close();
+    }
+
+    #[test]
+    fn narrow_samples() {
+        let channels = [
+            ("r", ndspy_sys::PkDspyUnsigned8),
+            ("g", ndspy_sys::PkDspyFloat16),
+            (
+                "b",
+                ndspy_sys::PkDspyUnsigned16 | ndspy_sys::PkDspyByteOrderHiLo,
+            ),
+            ("a", ndspy_sys::PkDspyUnsigned8),
+        ];
+
+        // 6 bytes of samples & 2 of padding per pixel.
+        let pixels = (0..16u16)
+            .flat_map(|i| {
+                let mut pixel = vec![i as u8 * 16];
+                pixel.extend_from_slice(&f16::from_f32(i as f32 / 4.).to_ne_bytes());
+                pixel.extend_from_slice(&(i * 4096).to_be_bytes());
+                pixel.extend_from_slice(&[255, 0xaa, 0xaa]);
+                pixel
+            })
+            .collect::<Vec<_>>();
+
+        // Streamed, and kept packed as tiles can not be streamed.
+        for parameters in [vec![], vec![("tile_size", Value::Int(&[16, 16]))]] {
+            let file_name = file_name("narrow.exr");
+            let display = Display::open(&file_name, 4, 4, &channels, &parameters).unwrap();
+            // Two buckets, the second one first.
+            assert_eq!(
+                ndspy_sys::PtDspyError_PkDspyErrorNone,
+                display.data_bytes(0, 2, 4, 2, 8, &pixels[64..])
+            );
+            assert_eq!(
+                ndspy_sys::PtDspyError_PkDspyErrorNone,
+                display.data_bytes(0, 0, 4, 2, 8, &pixels[..64])
+            );
+            assert_eq!(ndspy_sys::PtDspyError_PkDspyErrorNone, display.close());
+
+            let image = read_exr(&file_name);
+            for (i, (((r, g), b), a)) in channel(&image, "R")
+                .into_iter()
+                .zip(channel(&image, "G"))
+                .zip(channel(&image, "B"))
+                .zip(channel(&image, "A"))
+                .enumerate()
+            {
+                let i = i as f32;
+                assert_eq!(
+                    (i * 16. / 255., i / 4., i * 4096. / 65535., 1.),
+                    (r, g, b, a)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn data_and_display_windows() {
+        let samples = rgba(4, 2, |_, _| [1.; 4]);
+
+        // A crop window, an overscan region & the full frame.
+        for (origin, original_size, display_size) in [
+            (Some([3, 5]), Some([10, 8]), (10, 8)),
+            (Some([-2, -1]), Some([1, 1]), (1, 1)),
+            (None, None, (4, 2)),
+        ] {
+            let file_name = file_name("windows.exr");
+            let mut parameters = Vec::new();
+            if let Some(origin) = origin.as_ref() {
+                parameters.push(("origin", Value::Int(origin)));
+            }
+            if let Some(original_size) = original_size.as_ref() {
+                parameters.push(("OriginalSize", Value::Int(original_size)));
+            }
+            render(&file_name, 4, 2, &parameters, &samples);
+
+            let image = read_exr(&file_name);
+            let display_window = image.attributes.display_window;
+            assert_eq!(Vec2(0, 0), display_window.position);
+            assert_eq!(
+                display_size,
+                (display_window.size.width(), display_window.size.height())
+            );
+            let origin = origin.unwrap_or([0, 0]);
+            assert_eq!(
+                Vec2(origin[0], origin[1]),
+                image.layers[0].attributes.layer_position
+            );
+            assert_eq!(Vec2(4, 2), image.layers[0].size);
+        }
+    }
+
+    #[test]
+    fn screen_window() {
+        // A camera at the origin looking down +z whose lens is shifted
+        // to center the screen window at (0.25, -0.5), 1.6 wide &
+        // 1.6 × 3 / 4 high.
+        let (scale_x, scale_y) = (2. / 1.6, 2. / 1.2);
+        #[rustfmt::skip]
+        let camera_to_ndc = cgmath::Matrix4::new(
+            scale_x, 0., 0., 0.,
+            0., scale_y, 0., 0.,
+            -scale_x * 0.25, scale_y * 0.5, 1., 1.,
+            0., 0., -0.1, 0.,
+        );
+        let world_to_camera = cgmath::Matrix4::from_translation(cgmath::vec3(1., 2., 3.));
+        let world_to_ndc = camera_to_ndc * world_to_camera;
+        let (world_to_camera, world_to_ndc): (&[f32; 16], &[f32; 16]) =
+            (world_to_camera.as_ref(), world_to_ndc.as_ref());
+
+        let screen_window = |parameters: &[(&str, Value)]| {
+            let file_name = file_name("screen_window.exr");
+            render(&file_name, 4, 3, parameters, &rgba(4, 3, |_, _| [1.; 4]));
+            let attributes = read_exr(&file_name).layers[0].attributes.clone();
+            (
+                attributes.screen_window_center,
+                attributes.screen_window_width,
+            )
+        };
+
+        let (center, width) = screen_window(&[
+            ("Nl", Value::Float(world_to_camera)),
+            ("NP", Value::Float(world_to_ndc)),
+        ]);
+        assert!((center.x() - 0.25).abs() < 1e-5 && (center.y() + 0.5).abs() < 1e-5);
+        assert!((width - 1.6).abs() < 1e-5);
+
+        // An explicit screen window wins.
+        assert_eq!(
+            (Vec2(0.5, 0.), 2.),
+            screen_window(&[
+                ("Nl", Value::Float(world_to_camera)),
+                ("NP", Value::Float(world_to_ndc)),
+                ("ScreenWindow", Value::Float(&[-0.5, 1.5, -0.75, 0.75])),
+            ])
+        );
+
+        // Without a camera the defaults are kept.
+        assert_eq!((Vec2(0., 0.), 1.), screen_window(&[]));
+    }
+
+    #[test]
+    fn camera_attributes() {
+        let file_name = file_name("camera.exr");
+        render(
+            &file_name,
+            1,
+            1,
+            &[
+                ("focallength", Value::Float(&[35.])),
+                ("fstop", Value::Float(&[2.8])),
+                ("focaldistance", Value::Float(&[4.5])),
+                ("shutter", Value::Float(&[0.25, 0.75])),
+                ("filmback", Value::Float(&[36., 24.])),
+            ],
+            &rgba(1, 1, |_, _| [1.; 4]),
+        );
+
+        let attributes = read_exr(&file_name).layers[0].attributes.clone();
+        assert_eq!(
+            (Some(2.8), Some(4.5), Some(0.5)),
+            (attributes.aperture, attributes.focus, attributes.exposure)
+        );
+        let other = |name| attributes.other.get(&Text::from(name).unwrap());
+        assert_eq!(Some(&AttributeValue::F32(35.)), other("nominalFocalLength"));
+        assert_eq!(
+            Some(&AttributeValue::FloatVec2(Vec2(0.25, 0.75))),
+            other("shutter")
+        );
+        assert_eq!(
+            Some(&AttributeValue::FloatVec2(Vec2(36., 24.))),
+            other("sensorOverallDimensions")
+        );
+    }
+
+    #[test]
+    fn environment_maps() {
+        let world_to_ndc = cgmath::Matrix4::from_nonuniform_scale(2., 2., 1.);
+        let world_to_ndc: &[f32; 16] = world_to_ndc.as_ref();
+
+        for (parameters, environment_map) in [
+            (
+                vec![("envmap", Value::String(&["latlong"]))],
+                Some(EnvironmentMap::LatitudeLongitude),
+            ),
+            (
+                vec![("projection", Value::String(&["cubemap"]))],
+                Some(EnvironmentMap::Cube),
+            ),
+            (
+                vec![
+                    ("projection", Value::String(&["spherical"])),
+                    ("envmap", Value::String(&["none"])),
+                ],
+                None,
+            ),
+            (vec![("projection", Value::String(&["perspective"]))], None),
+        ] {
+            let file_name = file_name("envmap.exr");
+            let mut parameters = parameters;
+            parameters.push(("NP", Value::Float(world_to_ndc)));
+            render(&file_name, 2, 1, &parameters, &rgba(2, 1, |_, _| [1.; 4]));
+
+            let attributes = read_exr(&file_name).layers[0].attributes.clone();
+            assert_eq!(environment_map, attributes.environment_map);
+            // Environment maps have no perspective projection.
+            assert_eq!(
+                environment_map.is_none(),
+                attributes.world_to_normalized_device.is_some()
+            );
+        }
+    }
+
+    #[test]
+    fn multi_view() {
+        let file_name = file_name("stereo.exr");
+        let views = ["left", "right"]
+            .iter()
+            .map(|&view| {
+                Display::open(&file_name, 2, 2, &RGBA, &[("view", Value::String(&[view]))]).unwrap()
+            })
+            .collect::<Vec<_>>();
+        // All views are opened before the first bucket arrives.
+        views
+            .iter()
+            .enumerate()
+            .for_each(|(i, view)| view.send(&rgba(2, 2, |_, _| [i as f32, 0., 0., 1.]), 2));
+        views.into_iter().for_each(|view| {
+            view.close();
+        });
+
+        let image = read_exr(&file_name);
+        assert_eq!(1, image.layers.len());
+        let attributes = &image.layers[0].attributes;
+        assert_eq!(None, attributes.view_name);
+        assert_eq!(
+            vec!["left", "right"],
+            attributes
+                .multi_view_names
+                .as_ref()
+                .unwrap()
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        );
+        // The first view is the default one.
+        assert_eq!(vec![0.; 4], channel(&image, "R"));
+        assert_eq!(vec![1.; 4], channel(&image, "right.R"));
+        assert_eq!(vec![1.; 4], channel(&image, "right.A"));
+    }
+
+    #[test]
+    fn multi_layer() {
+        let file_name = file_name("layers.exr");
+        let albedo_channels = [
+            ("albedo.000.r", ndspy_sys::PkDspyFloat32),
+            ("albedo.000.g", ndspy_sys::PkDspyFloat32),
+            ("albedo.000.b", ndspy_sys::PkDspyFloat32),
+        ];
+        let beauty = Display::open(&file_name, 2, 1, &RGBA, &[]).unwrap();
+        let albedo = Display::open(&file_name, 2, 1, &albedo_channels, &[]).unwrap();
+        let depth = Display::open(
+            &file_name,
+            2,
+            1,
+            &[("z", ndspy_sys::PkDspyFloat32)],
+            &[("layername", Value::String(&["depth"]))],
+        )
+        .unwrap();
+
+        beauty.send(&rgba(2, 1, |_, _| [1., 1., 1., 1.]), 2);
+        albedo.send(&[0.5, 0.25, 0.125, 0.5, 0.25, 0.125], 2);
+        depth.send(&[3., 4.], 2);
+        // The image is written once the last one is closed.
+        beauty.close();
+        albedo.close();
+        assert!(!std::path::Path::new(&file_name).exists());
+        depth.close();
+
+        let image = read_exr(&file_name);
+        let layers = image
+            .layers
+            .iter()
+            .map(|layer| {
+                (
+                    layer.attributes.layer_name.as_ref().unwrap().to_string(),
+                    layer
+                        .channels
+                        .iter()
+                        .map(|channel| channel.name.to_string())
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("RGBA".to_string(), vec!["A", "B", "G", "R"]),
+                ("albedo".to_string(), vec!["B", "G", "R"]),
+                ("depth".to_string(), vec!["Z"]),
+            ],
+            layers
+                .iter()
+                .map(|(name, channels)| (
+                    name.clone(),
+                    channels.iter().map(String::as_str).collect::<Vec<_>>()
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            simple_image::Samples::F32(vec![0.25; 2]),
+            image.layers[1].channels[1].samples
+        );
+    }
+
+    #[test]
+    fn checkpoints() {
+        let file_name = file_name("checkpointed.exr");
+        let checkpoint = file_name.replace(".exr", "_checkpoint.exr");
+        let display = Display::open(
+            &file_name,
+            2,
+            2,
+            &RGBA,
+            &[("checkpoint_interval", Value::Float(&[0.2]))],
+        )
+        .unwrap();
+
+        // The first row arrives once the interval has passed.
+        std::thread::sleep(std::time::Duration::from_millis(250));
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            display.data(0, 0, 2, 1, &[1.; 8])
+        );
+        wait_for_checkpoint(&display);
+        let image = read_exr(&checkpoint);
+        assert!(is_marked_incomplete(&image));
+        assert_eq!(vec![1., 1., 0., 0.], channel(&image, "R"));
+
+        assert_eq!(
+            ndspy_sys::PtDspyError_PkDspyErrorNone,
+            display.data(0, 1, 2, 1, &[1.; 8])
+        );
+        display.close();
+
+        // The final image replaces the checkpoint.
+        assert!(!std::path::Path::new(&checkpoint).exists());
+        let image = read_exr(&file_name);
+        assert!(!is_marked_incomplete(&image));
+        assert_eq!(vec![1.; 4], channel(&image, "R"));
+    }
+
+    /// Checkpoints are written in the background.
+    fn wait_for_checkpoint(display: &Display) {
+        let image = unsafe { &mut *(display.handle as *mut crate::ImageData) };
+        image.checkpoint.take().unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn checkpoints_are_written_like_the_final_image() {
+        for premultiply in [0, 1] {
+            let file_name = file_name("checkpoint_alpha.exr");
+            let checkpoint = file_name.replace(".exr", "_checkpoint.exr");
+            let display = Display::open(
+                &file_name,
+                2,
+                1,
+                &RGBA,
+                &[
+                    ("checkpoint_interval", Value::Float(&[0.01])),
+                    ("premultiply", Value::Int(&[premultiply])),
+                    ("compression", Value::String(&["zip"])),
+                    ("tile_size", Value::Int(&[16, 16])),
+                ],
+            )
+            .unwrap();
+
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            display.data(0, 0, 1, 1, &[0.25, 0.25, 0.25, 0.5]);
+            wait_for_checkpoint(&display);
+
+            let image = read_exr(&checkpoint);
+            let expected_red = if 0 == premultiply { 0.5 } else { 0.25 };
+            assert_eq!(vec![expected_red, 0.], channel(&image, "R"));
+            assert_eq!(vec![0.5, 0.], channel(&image, "A"));
+            // Quick to write, whatever the final image uses.
+            assert_eq!(Compression::RLE, image.layers[0].compression);
+
+            display.close();
+        }
+    }
+
+    #[test]
+    fn bucket_placement() {
+        let samples = rgba(5, 3, |x, y| [x as f32, y as f32, 0., 1.]);
+        let bucket = |x: usize, y: usize, width: usize, height: usize| {
+            (y..y + height)
+                .flat_map(|y| samples[4 * (x + 5 * y)..4 * (x + width + 5 * y)].to_vec())
+                .collect::<Vec<_>>()
+        };
+
+        // Streamed & buffered.
+        for parameters in [vec![], vec![("tile_size", Value::Int(&[16, 16]))]] {
+            let file_name = file_name("buckets.exr");
+            let display = Display::open(&file_name, 5, 3, &RGBA, &parameters).unwrap();
+
+            // Out of the frame or empty.
+            for (x_min, x_max, y_min, y_max) in [
+                (-1, 1, 0, 1),
+                (4, 6, 0, 1),
+                (0, 1, 2, 4),
+                (2, 2, 0, 1),
+                (0, 1, 1, 0),
+            ] {
+                assert_eq!(
+                    ndspy_sys::PtDspyError_PkDspyErrorBadParams,
+                    display.data_bounds(x_min, x_max, y_min, y_max, &samples)
+                );
+            }
+
+            // Bottom up & right to left.
+            for (x, y, width, height) in [(4, 2, 1, 1), (0, 2, 4, 1), (2, 0, 3, 2), (0, 0, 2, 2)] {
+                assert_eq!(
+                    ndspy_sys::PtDspyError_PkDspyErrorNone,
+                    display.data(x, y, width, height, &bucket(x, y, width, height))
+                );
+            }
+            display.close();
+
+            let image = read_exr(&file_name);
+            assert!(!is_marked_incomplete(&image));
+            assert_eq!(
+                samples.chunks(4).map(|pixel| pixel[0]).collect::<Vec<_>>(),
+                channel(&image, "R")
+            );
+            assert_eq!(
+                samples.chunks(4).map(|pixel| pixel[1]).collect::<Vec<_>>(),
+                channel(&image, "G")
+            );
+        }
+    }
+}
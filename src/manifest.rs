@@ -0,0 +1,307 @@
+//! A JSON sidecar describing a written image for asset systems.
+use crate::{channel_layout, crc::Crc32, ImageData};
+use exr::compression::Compression;
+use std::{
+    fmt::Write,
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+/// Writes `<file_name>.json` describing the `images` that were written
+/// to `file_name`.
+pub fn write(images: &[ImageData], file_name: &str) {
+    let manifest_file_name = format!("{}.json", file_name);
+
+    let checksum = match checksum(file_name) {
+        Ok(checksum) => checksum,
+        Err(e) => {
+            eprintln!("[r-display] could not read {}: {}", file_name, e);
+            return;
+        }
+    };
+
+    let image = &images[0];
+    let mut json = String::new();
+
+    json.push_str("{\n");
+    writeln!(json, "  \"file\": {},", string(file_name)).unwrap();
+    writeln!(json, "  \"crc32\": \"{:08x}\",", checksum).unwrap();
+    writeln!(json, "  \"width\": {},", image.width).unwrap();
+    writeln!(json, "  \"height\": {},", image.height).unwrap();
+    writeln!(
+        json,
+        "  \"pixelAspectRatio\": {},",
+        number(image.pixel_aspect)
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "  \"compression\": {},",
+        string(compression_name(image.compression))
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "  \"renderTime\": {},",
+        image
+            .render_time
+            .map(|render_time| number(render_time.as_secs_f32()))
+            .unwrap_or_else(|| "null".to_string())
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "  \"worldToCamera\": {},",
+        matrix(image.world_to_camera)
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "  \"worldToScreen\": {},",
+        matrix(image.world_to_screen)
+    )
+    .unwrap();
+
+    json.push_str("  \"layers\": [\n");
+    for (i, image) in images.iter().enumerate() {
+        json.push_str("    {\n");
+        writeln!(
+            json,
+            "      \"name\": {},",
+            image
+                .layer_name()
+                .map(|name| string(&name))
+                .unwrap_or_else(|| "null".to_string())
+        )
+        .unwrap();
+        writeln!(
+            json,
+            "      \"view\": {},",
+            image
+                .view
+                .as_ref()
+                .map(|view| string(view))
+                .unwrap_or_else(|| "null".to_string())
+        )
+        .unwrap();
+        writeln!(
+            json,
+            "      \"denoise\": {{ \"amount\": {}, \"albedo\": {}, \"normal\": {} }},",
+            number(image.denoise),
+            image.albedo_index.is_some(),
+            image.albedo_index.is_some() && image.normal_index.is_some()
+        )
+        .unwrap();
+
+        json.push_str("      \"channels\": [\n");
+        let layout = channel_layout(image);
+        for (j, (name, index, _)) in layout.iter().enumerate() {
+            let statistics = Statistics::new(image, *index);
+            write!(
+                json,
+                "        {{ \"name\": {}, \"type\": \"float\", \"min\": {}, \"max\": {}, \"mean\": {}, \"nonFinite\": {} }}",
+                string(name),
+                number(statistics.min),
+                number(statistics.max),
+                number(statistics.mean),
+                statistics.non_finite
+            )
+            .unwrap();
+            json.push_str(if j + 1 < layout.len() { ",\n" } else { "\n" });
+        }
+        json.push_str("      ]\n");
+
+        json.push_str(if i + 1 < images.len() {
+            "    },\n"
+        } else {
+            "    }\n"
+        });
+    }
+    json.push_str("  ]\n");
+    json.push_str("}\n");
+
+    crate::write_to_file(&manifest_file_name, |temporary_file_name| {
+        std::fs::write(temporary_file_name, json)?;
+        Ok(())
+    });
+}
+
+/// CRC-32 of the file `file_name`, read piece by piece.
+fn checksum(file_name: &str) -> std::io::Result<u32> {
+    let mut file = BufReader::new(File::open(file_name)?);
+    let mut crc = Crc32::new();
+
+    loop {
+        let bytes = file.fill_buf()?;
+        if bytes.is_empty() {
+            break Ok(crc.value());
+        }
+        crc.update(bytes);
+
+        let len = bytes.len();
+        file.consume(len);
+    }
+}
+
+/// Statistics of the finite samples of one channel.
+struct Statistics {
+    min: f32,
+    max: f32,
+    mean: f32,
+    /// Number of NaN or infinite samples.
+    non_finite: usize,
+}
+
+impl Statistics {
+    fn new(image: &ImageData, index: usize) -> Self {
+        let mut min = f32::INFINITY;
+        let mut max = f32::NEG_INFINITY;
+        let mut sum = 0f64;
+        let mut non_finite = 0;
+
+        image
+            .data
+            .chunks(image.num_channels)
+            .map(|pixel| pixel[index])
+            .for_each(|sample| {
+                if sample.is_finite() {
+                    min = min.min(sample);
+                    max = max.max(sample);
+                    sum += sample as f64;
+                } else {
+                    non_finite += 1;
+                }
+            });
+
+        let num_finite = image.width * image.height - non_finite;
+
+        Statistics {
+            min,
+            max,
+            mean: if 0 < num_finite {
+                (sum / num_finite as f64) as _
+            } else {
+                f32::NAN
+            },
+            non_finite,
+        }
+    }
+}
+
+/// The name the `compression` parameter uses for `compression`.
+fn compression_name(compression: Compression) -> &'static str {
+    match compression {
+        Compression::Uncompressed => "none",
+        Compression::RLE => "rle",
+        Compression::ZIP1 => "zips",
+        Compression::ZIP16 => "zip",
+        Compression::PIZ => "piz",
+        Compression::PXR24 => "pxr24",
+        Compression::B44 => "b44",
+        Compression::B44A => "b44a",
+        Compression::DWAA(_) => "dwaa",
+        Compression::DWAB => "dwab",
+    }
+}
+
+fn string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+
+    json.push('"');
+    value.chars().for_each(|c| match c {
+        '"' => json.push_str("\\\""),
+        '\\' => json.push_str("\\\\"),
+        '\n' => json.push_str("\\n"),
+        c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+        c => json.push(c),
+    });
+    json.push('"');
+
+    json
+}
+
+/// JSON has no NaN or infinity.
+fn number(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+fn matrix(matrix: Option<[f32; 16]>) -> String {
+    match matrix {
+        Some(matrix) => format!(
+            "[{}]",
+            matrix
+                .iter()
+                .map(|&value| number(value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        None => "null".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crc::crc32, file_name::temporary, test_util::*};
+
+    #[test]
+    fn escaping() {
+        assert_eq!(r#""a\"b\\c\nd\u0009""#, string("a\"b\\c\nd\t"));
+        assert_eq!("null", number(f32::NAN));
+        assert_eq!("null", number(f32::INFINITY));
+        assert_eq!("-0.5", number(-0.5));
+        assert_eq!("null", matrix(None));
+    }
+
+    #[test]
+    fn manifest() {
+        let file_name = file_name("manifest.exr");
+        render(
+            &file_name,
+            2,
+            1,
+            &[
+                ("manifest", Value::Int(&[1])),
+                ("compression", Value::String(&["piz"])),
+                ("tile_size", Value::Int(&[2, 1])),
+            ],
+            &[1., 0.5, 0., 1., f32::NAN, 1.5, 0., 1.],
+        );
+
+        let json = std::fs::read_to_string(format!("{}.json", file_name)).unwrap();
+        let crc = format!(
+            "\"crc32\": \"{:08x}\"",
+            crc32(&std::fs::read(&file_name).unwrap())
+        );
+        for line in [
+            crc.as_str(),
+            "\"width\": 2,",
+            "\"height\": 1,",
+            "\"compression\": \"piz\",",
+            "\"worldToCamera\": null,",
+            "\"name\": \"RGBA\",",
+            "\"denoise\": { \"amount\": 0, \"albedo\": false, \"normal\": false },",
+            "{ \"name\": \"R\", \"type\": \"float\", \"min\": 1, \"max\": 1, \"mean\": 1, \"nonFinite\": 1 }\n",
+            "{ \"name\": \"G\", \"type\": \"float\", \"min\": 0.5, \"max\": 1.5, \"mean\": 1, \"nonFinite\": 0 },",
+        ] {
+            assert!(json.contains(line), "{} not in {}", line, json);
+        }
+        assert!(json.trim_end().ends_with("]\n}"));
+        let manifest_file_name = format!("{}.json", file_name);
+        assert!(!std::path::Path::new(&temporary(&manifest_file_name)).exists());
+    }
+
+    #[test]
+    fn checksum_of_large_file() {
+        // Larger than the buffer the file is read through.
+        let bytes = (0..100_000).map(|i| (i * 7919) as u8).collect::<Vec<_>>();
+        let file_name = file_name("checksum.bin");
+        std::fs::write(&file_name, &bytes).unwrap();
+
+        assert_eq!(crc32(&bytes), checksum(&file_name).unwrap());
+    }
+}
@@ -0,0 +1,98 @@
+//! What to do with a frame the renderer stopped before all pixels
+//! arrived.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnAbort {
+    /// Do not write the image.
+    Discard,
+    /// Write the pixels as they arrived, without denoising.
+    Raw,
+    /// Process and write the image as usual.
+    Mark,
+}
+
+impl OnAbort {
+    /// Parses the value of the `on_abort` parameter.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "discard" => Some(OnAbort::Discard),
+            "raw" => Some(OnAbort::Raw),
+            "mark" => Some(OnAbort::Mark),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use exr::meta::attribute::{AttributeValue, Text};
+
+    /// Renders the top half of a 2 × 2 frame, tiled so it is not
+    /// streamed.
+    fn render_top_half(file_name: &str, on_abort: &str, denoise: f32) {
+        let display = Display::open(
+            file_name,
+            2,
+            2,
+            &RGBA,
+            &[
+                ("on_abort", Value::String(&[on_abort])),
+                ("denoise", Value::Float(&[denoise])),
+                ("tile_size", Value::Int(&[16, 16])),
+            ],
+        )
+        .unwrap();
+        display.data(0, 0, 2, 1, &[0.5, 0.25, 0.125, 1., 0.5, 0.25, 0.125, 1.]);
+        display.close();
+    }
+
+    fn attribute(image: &exr::prelude::simple_image::Image, name: &str) -> Option<AttributeValue> {
+        image.layers[0]
+            .attributes
+            .other
+            .get(&Text::from(name).unwrap())
+            .cloned()
+    }
+
+    #[test]
+    fn from_name() {
+        assert_eq!(Some(OnAbort::Discard), OnAbort::from_name("Discard"));
+        assert_eq!(Some(OnAbort::Raw), OnAbort::from_name("raw"));
+        assert_eq!(Some(OnAbort::Mark), OnAbort::from_name("mark"));
+        assert_eq!(None, OnAbort::from_name("keep"));
+    }
+
+    #[test]
+    fn policies() {
+        // Written as usual but marked.
+        let mark = file_name("mark.exr");
+        render_top_half(&mark, "mark", 1.);
+        let image = read_exr(&mark);
+        assert_eq!(
+            Some(AttributeValue::I32(1)),
+            attribute(&image, "renderIncomplete")
+        );
+        assert_eq!(Some(AttributeValue::F32(1.)), attribute(&image, "denoise"));
+
+        // Written as the pixels arrived.
+        let raw = file_name("raw.exr");
+        render_top_half(&raw, "raw", 1.);
+        let image = read_exr(&raw);
+        assert_eq!(
+            Some(AttributeValue::I32(1)),
+            attribute(&image, "renderIncomplete")
+        );
+        assert_eq!(Some(AttributeValue::F32(0.)), attribute(&image, "denoise"));
+        assert_eq!(vec![0.5, 0.5, 0., 0.], channel(&image, "R"));
+
+        // Not written; an existing file is kept.
+        let file_name = file_name("discard.exr");
+        render_top_half(&file_name, "discard", 0.);
+        assert!(!std::path::Path::new(&file_name).exists());
+        std::fs::write(&file_name, "keep").unwrap();
+        render_top_half(&file_name, "discard", 0.);
+        assert_eq!(b"keep", &std::fs::read(&file_name).unwrap()[..]);
+    }
+}
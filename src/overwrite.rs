@@ -0,0 +1,120 @@
+//! What to do if the file we are about to write already exists.
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overwrite {
+    /// Replace the existing file.
+    Always,
+    /// Keep the existing file and do not write the image.
+    Never,
+    /// Keep the existing file and write the image next to it with a
+    /// version number appended, e.g. `beauty_v002.exr`.
+    Version,
+}
+
+impl Overwrite {
+    /// Parses the value of the `overwrite` parameter.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "always" => Some(Overwrite::Always),
+            "never" => Some(Overwrite::Never),
+            "version" => Some(Overwrite::Version),
+            _ => None,
+        }
+    }
+
+    /// The name of the file to write to or `None` if nothing should be
+    /// written.
+    pub fn file_name(&self, file_name: &str) -> Option<String> {
+        if !Path::new(file_name).exists() {
+            return Some(file_name.to_string());
+        }
+
+        match self {
+            Overwrite::Always => Some(file_name.to_string()),
+            Overwrite::Never => None,
+            Overwrite::Version => {
+                let path = Path::new(file_name);
+                let stem = path.file_stem()?.to_string_lossy();
+                let extension = path
+                    .extension()
+                    .map(|extension| format!(".{}", extension.to_string_lossy()))
+                    .unwrap_or_default();
+
+                (1..)
+                    .map(|version| {
+                        path.with_file_name(format!("{}_v{:03}{}", stem, version, extension))
+                    })
+                    .find(|path| !path.exists())
+                    .map(|path| path.to_string_lossy().into_owned())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    #[test]
+    fn versions() {
+        let file_name = file_name("beauty.exr");
+        assert_eq!(
+            Some(file_name.clone()),
+            Overwrite::Never.file_name(&file_name)
+        );
+
+        std::fs::write(&file_name, "").unwrap();
+        let version = |version| file_name.replace("beauty.exr", version);
+        assert_eq!(None, Overwrite::Never.file_name(&file_name));
+        assert_eq!(
+            Some(file_name.clone()),
+            Overwrite::Always.file_name(&file_name)
+        );
+        assert_eq!(
+            Some(version("beauty_v001.exr")),
+            Overwrite::Version.file_name(&file_name)
+        );
+
+        std::fs::write(version("beauty_v001.exr"), "").unwrap();
+        assert_eq!(
+            Some(version("beauty_v002.exr")),
+            Overwrite::Version.file_name(&file_name)
+        );
+    }
+
+    #[test]
+    fn existing_files() {
+        let file_name = file_name("existing.exr");
+        let directory = Path::new(&file_name).parent().unwrap();
+        let render_with = |overwrite| {
+            render(
+                &file_name,
+                1,
+                1,
+                &[("overwrite", Value::String(&[overwrite]))],
+                &rgba(1, 1, |_, _| [1.; 4]),
+            )
+        };
+        let version = file_name.replace(".exr", "_v001.exr");
+
+        std::fs::write(&file_name, "keep").unwrap();
+        render_with("never");
+        assert_eq!(b"keep", &std::fs::read(&file_name).unwrap()[..]);
+
+        render_with("version");
+        assert_eq!(b"keep", &std::fs::read(&file_name).unwrap()[..]);
+        read_exr(&version);
+
+        render_with("always");
+        read_exr(&file_name);
+
+        // No temporary files are left behind.
+        assert!(!std::fs::read_dir(directory).unwrap().any(|entry| entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .contains("existing.exr.")));
+    }
+}
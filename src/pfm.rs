@@ -0,0 +1,48 @@
+//! A Portable Float Map (`.pfm`) encoder.
+use std::io::Write;
+
+/// Writes `rgb`, interleaved RGB samples of `width` × `height` pixels.
+pub fn write(file_name: &str, width: usize, height: usize, rgb: &[f32]) -> std::io::Result<()> {
+    let mut pfm = Vec::with_capacity(12 * width * height + 32);
+
+    // A negative scale means little endian samples.
+    write!(pfm, "PF\n{} {}\n-1.0\n", width, height)?;
+
+    // Rows are stored bottom to top.
+    rgb.chunks(3 * width)
+        .take(height)
+        .rev()
+        .flatten()
+        .for_each(|sample| pfm.extend_from_slice(&sample.to_le_bytes()));
+
+    std::fs::File::create(file_name)?.write_all(&pfm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::file_name;
+    use std::convert::TryInto;
+
+    #[test]
+    fn layout() {
+        // Two rows of three pixels.
+        let rgb = (0..18).map(|i| i as f32 + 0.5).collect::<Vec<_>>();
+
+        let file_name = file_name("layout.pfm");
+        write(&file_name, 3, 2, &rgb).unwrap();
+        let pfm = std::fs::read(&file_name).unwrap();
+
+        // A negative scale says the samples are little endian.
+        let header = b"PF\n3 2\n-1.0\n";
+        assert_eq!(header, &pfm[..header.len()]);
+
+        let samples = pfm[header.len()..]
+            .chunks(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        // The bottom row comes first.
+        assert_eq!(rgb[9..], samples[..9]);
+        assert_eq!(rgb[..9], samples[9..]);
+    }
+}
@@ -0,0 +1,104 @@
+//! Writes 8 & 16 bit RGB(A) PNGs.
+use std::{fs::File, io::BufWriter};
+
+/// Writes `samples`, interleaved `num_channels` (3 or 4) channels of
+/// `width` × `height` pixels, with `bit_depth` (8 or 16) bits each.
+pub fn write(
+    file_name: &str,
+    width: usize,
+    height: usize,
+    num_channels: usize,
+    bit_depth: u8,
+    samples: &[u16],
+) -> std::io::Result<()> {
+    let mut encoder = ::png::Encoder::new(
+        BufWriter::new(File::create(file_name)?),
+        width as _,
+        height as _,
+    );
+    encoder.set_color(if 4 == num_channels {
+        ::png::ColorType::Rgba
+    } else {
+        ::png::ColorType::Rgb
+    });
+    encoder.set_depth(if 16 == bit_depth {
+        ::png::BitDepth::Sixteen
+    } else {
+        ::png::BitDepth::Eight
+    });
+
+    // PNGs store samples big endian.
+    let data = if 16 == bit_depth {
+        samples
+            .iter()
+            .flat_map(|sample| sample.to_be_bytes())
+            .collect::<Vec<_>>()
+    } else {
+        samples.iter().map(|&sample| sample as u8).collect()
+    };
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::test_util::file_name;
+
+    /// Reads a PNG.
+    ///
+    /// Returns width, height, number of channels, bit depth & samples.
+    pub fn read(file_name: &str) -> (usize, usize, usize, u8, Vec<u16>) {
+        let mut reader = ::png::Decoder::new(File::open(file_name).unwrap())
+            .read_info()
+            .unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+
+        let num_channels = match info.color_type {
+            ::png::ColorType::Rgb => 3,
+            ::png::ColorType::Rgba => 4,
+            color_type => panic!("unexpected color type {:?}", color_type),
+        };
+        let bit_depth = info.bit_depth as u8;
+        let samples = data[..info.buffer_size()]
+            .chunks(bit_depth as usize / 8)
+            .map(|sample| match sample {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                _ => sample[0] as u16,
+            })
+            .collect();
+
+        (
+            info.width as _,
+            info.height as _,
+            num_channels,
+            bit_depth,
+            samples,
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let (width, height) = (7, 5);
+
+        for (num_channels, bit_depth) in [(3, 8), (4, 8), (3, 16), (4, 16)] {
+            let max = if 8 == bit_depth { 255 } else { 65535 };
+            let samples = (0..width * height * num_channels)
+                .map(|i| (i * 7919 % (max + 1)) as u16)
+                .collect::<Vec<_>>();
+
+            let file_name = file_name("round_trip.png");
+            write(&file_name, width, height, num_channels, bit_depth, &samples).unwrap();
+
+            assert_eq!(
+                (width, height, num_channels, bit_depth, samples),
+                read(&file_name)
+            );
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Reporting how far the render has come.
+//!
+//! Progress can be printed to stderr, written to a file a farm manager
+//! can poll and/or passed to a C callback registered through
+//! [`RDisplaySetProgressCallback`].
+use std::{
+    ffi::CString,
+    os::raw::{c_char, c_void},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Called with the file name of the image, its percentage complete and
+/// the estimated seconds left (negative if unknown yet).
+pub type ProgressCallback =
+    extern "C" fn(file_name: *const c_char, percent: f32, eta: f32, user_data: *mut c_void);
+
+/// The callback and its user data (stored as an address so this can be
+/// shared between threads).
+static CALLBACK: Mutex<Option<(ProgressCallback, usize)>> = Mutex::new(None);
+
+/// Registers `callback` to be called whenever pixels arrive, replacing
+/// any previous one. Pass a null `callback` to unregister.
+#[no_mangle]
+pub extern "C" fn RDisplaySetProgressCallback(
+    callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) {
+    *CALLBACK.lock().unwrap() = callback.map(|callback| (callback, user_data as usize));
+}
+
+#[derive(Debug)]
+pub struct Progress {
+    /// Print progress to stderr.
+    log: bool,
+    /// Write progress to this file.
+    file: Option<String>,
+    /// Minimum time between reports to stderr or the file.
+    interval: Duration,
+    start: Instant,
+    last_report: Option<Instant>,
+    /// The renderer reports its progress itself.
+    from_renderer: bool,
+}
+
+impl Progress {
+    pub fn new(log: bool, file: Option<String>, interval: Duration) -> Self {
+        Progress {
+            log,
+            file,
+            interval,
+            start: Instant::now(),
+            last_report: None,
+            from_renderer: false,
+        }
+    }
+
+    /// Reports that `finished_pixels` of `total_pixels` of the image
+    /// `file_name` have arrived.
+    pub fn update(&mut self, file_name: &str, finished_pixels: usize, total_pixels: usize) {
+        // The renderer knows better, e.g. for progressive renders.
+        if self.from_renderer {
+            return;
+        }
+
+        self.report(
+            file_name,
+            100. * finished_pixels.min(total_pixels) as f32 / total_pixels as f32,
+        );
+    }
+
+    /// Reports the progress of the image `file_name` as told by the
+    /// renderer, in percent.
+    pub fn update_from_renderer(&mut self, file_name: &str, percent: f32) {
+        self.from_renderer = true;
+        self.report(file_name, percent.clamp(0., 100.));
+    }
+
+    fn report(&mut self, file_name: &str, percent: f32) {
+        let eta = if 0. < percent {
+            self.start.elapsed().as_secs_f32() * (100. - percent) / percent
+        } else {
+            -1.
+        };
+
+        // The lock is released before the call so the callback can
+        // register another one.
+        let callback = *CALLBACK.lock().unwrap();
+        if let Some((callback, user_data)) = callback {
+            let file_name = CString::new(file_name).unwrap_or_default();
+            callback(file_name.as_ptr(), percent, eta, user_data as *mut _);
+        }
+
+        // Always report completion.
+        if percent < 100.
+            && self
+                .last_report
+                .is_some_and(|last_report| last_report.elapsed() < self.interval)
+        {
+            return;
+        }
+        self.last_report = Some(Instant::now());
+
+        if self.log {
+            eprintln!(
+                "[r-display] {}: {:.1}% (ETA {})",
+                file_name,
+                percent,
+                format_eta(eta)
+            );
+        }
+
+        if let Some(file) = &self.file {
+            // Write to a temporary file first so whoever polls the file
+            // never reads half of it.
+            let temporary_file = crate::file_name::temporary(file);
+            std::fs::write(
+                &temporary_file,
+                format!("{{ \"percent\": {:.1}, \"eta\": {:.0} }}\n", percent, eta),
+            )
+            .and_then(|_| std::fs::rename(&temporary_file, file))
+            .unwrap_or_else(|e| {
+                eprintln!("[r-display] could not write progress to {}: {}", file, e)
+            });
+        }
+    }
+}
+
+/// Formats `eta` seconds as `h:mm:ss`.
+fn format_eta(eta: f32) -> String {
+    if eta < 0. {
+        return "unknown".to_string();
+    }
+
+    let seconds = eta.round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use std::{
+        ffi::CStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc,
+        },
+    };
+
+    /// Counts its calls for `reentrant.exr` and unregisters itself.
+    extern "C" fn unregister(file_name: *const c_char, _: f32, _: f32, user_data: *mut c_void) {
+        if b"reentrant.exr" == unsafe { CStr::from_ptr(file_name) }.to_bytes() {
+            unsafe { &*(user_data as *const AtomicUsize) }.fetch_add(1, Ordering::SeqCst);
+        }
+        RDisplaySetProgressCallback(None, std::ptr::null_mut());
+    }
+
+    #[test]
+    fn callback_can_unregister_itself() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            RDisplaySetProgressCallback(Some(unregister), &CALLS as *const _ as *mut _);
+            let mut progress = Progress::new(false, None, Duration::ZERO);
+            progress.update("reentrant.exr", 1, 2);
+            progress.update("reentrant.exr", 2, 2);
+            sender.send(()).unwrap();
+        });
+
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("deadlocked");
+        assert_eq!(1, CALLS.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn eta() {
+        assert_eq!("unknown", format_eta(-1.));
+        assert_eq!("0:00:00", format_eta(0.2));
+        assert_eq!("1:01:01", format_eta(3661.));
+    }
+
+    #[test]
+    fn progress_file() {
+        let progress_file = file_name("progress.json");
+        let display = Display::open(
+            &file_name("progress.exr"),
+            2,
+            2,
+            &RGBA,
+            &[
+                ("progress_file", Value::String(&[&progress_file])),
+                ("progress_interval", Value::Float(&[0.])),
+            ],
+        )
+        .unwrap();
+        let percent = || {
+            let json = std::fs::read_to_string(&progress_file).unwrap();
+            assert!(json.starts_with("{ \"percent\": ") && json.contains(", \"eta\": "));
+            json[13..json.find(',').unwrap()].parse::<f32>().unwrap()
+        };
+
+        display.data(0, 0, 2, 1, &[1.; 8]);
+        assert_eq!(50., percent());
+
+        // Once the renderer reports progress itself pixels no longer
+        // count.
+        let mut render_progress: ndspy_sys::PtDspyRenderProgressFuncPtr = None;
+        display.query(
+            ndspy_sys::PtDspyQueryType_PkRenderProgress,
+            &mut render_progress,
+        );
+        unsafe { render_progress.unwrap()(display.handle, 75.) };
+        assert_eq!(75., percent());
+        display.data(0, 1, 2, 1, &[1.; 8]);
+        assert_eq!(75., percent());
+
+        display.close();
+    }
+}
@@ -0,0 +1,180 @@
+//! Information about where, when and by whom an image was rendered.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The local time formatted as required by the EXR `capDate` attribute
+/// (`YYYY:MM:DD hh:mm:ss`) and the matching `utcOffset` in seconds
+/// (UTC = local time + offset).
+#[cfg(unix)]
+pub fn capture_date() -> (String, f32) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as libc::time_t)
+        .unwrap_or(0);
+
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return utc_capture_date();
+    }
+
+    (
+        format!(
+            "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        ),
+        -tm.tm_gmtoff as f32,
+    )
+}
+
+#[cfg(not(unix))]
+pub fn capture_date() -> (String, f32) {
+    utc_capture_date()
+}
+
+/// Fallback if we can not get at the local time zone.
+fn utc_capture_date() -> (String, f32) {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    // Days since 1970-01-01 to civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let seconds_of_day = seconds % 86400;
+
+    (
+        format!(
+            "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            seconds_of_day / 3600,
+            (seconds_of_day / 60) % 60,
+            seconds_of_day % 60
+        ),
+        0.,
+    )
+}
+
+/// The name of the user running the renderer.
+pub fn user_name() -> Option<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .ok()
+}
+
+/// The name of the machine we are running on.
+#[cfg(unix)]
+pub fn host_name() -> Option<String> {
+    let mut buffer = [0u8; 256];
+
+    if 0 == unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut _, buffer.len()) } {
+        let len = buffer.iter().position(|&c| 0 == c).unwrap_or(buffer.len());
+        Some(String::from_utf8_lossy(&buffer[..len]).into_owned())
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn host_name() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use exr::meta::attribute::{AttributeValue, Text};
+
+    /// Seconds since midnight of a `YYYY:MM:DD hh:mm:ss` date.
+    fn time_of_day(date: &str) -> i64 {
+        let bytes = date.as_bytes();
+        assert_eq!(19, bytes.len(), "{}", date);
+        assert_eq!((b':', b':', b' '), (bytes[4], bytes[7], bytes[10]));
+        assert_eq!((b':', b':'), (bytes[13], bytes[16]));
+
+        date[11..].split(':').fold(0, |seconds, part| {
+            60 * seconds + part.parse::<i64>().unwrap()
+        })
+    }
+
+    #[test]
+    fn capture_date_matches_utc() {
+        let (utc, _) = utc_capture_date();
+        let (local, utc_offset) = capture_date();
+
+        // UTC = local time + offset, give or take a second passing.
+        let difference =
+            (time_of_day(&local) + utc_offset as i64 - time_of_day(&utc)).rem_euclid(86400);
+        assert!(difference <= 1 || 86399 <= difference);
+    }
+
+    #[test]
+    fn attributes() {
+        // Streamed and buffered images.
+        for tile_size in [None, Some([16, 16])] {
+            let file_name = file_name("provenance.exr");
+            let mut parameters = vec![
+                ("comments", Value::String(&["first light"])),
+                ("Software", Value::String(&["renderer 1.0"])),
+            ];
+            if let Some(tile_size) = tile_size.as_ref() {
+                parameters.push(("tile_size", Value::Int(tile_size)));
+            }
+            render(&file_name, 1, 1, &parameters, &rgba(1, 1, |_, _| [1.; 4]));
+
+            let image = read_exr(&file_name);
+            let attributes = &image.layers[0].attributes;
+            assert!(attributes.comments.as_ref().unwrap().eq("first light"));
+            assert!(attributes
+                .software_name
+                .as_ref()
+                .unwrap()
+                .to_string()
+                .starts_with("renderer 1.0"));
+            time_of_day(&attributes.capture_date.as_ref().unwrap().to_string());
+            assert!(attributes.utc_offset.is_some());
+            assert_eq!(
+                user_name(),
+                attributes.owner.as_ref().map(|owner| owner.to_string())
+            );
+
+            let other = |name: &str| attributes.other.get(&Text::from(name).unwrap());
+            match (other("hostName"), host_name()) {
+                (Some(AttributeValue::Text(name)), Some(host_name)) => {
+                    assert!(name.eq(&host_name))
+                }
+                (None, None) => (),
+                attribute => panic!("hostName {:?}", attribute),
+            }
+            assert!(matches!(
+                other("renderTime"),
+                Some(&AttributeValue::F32(seconds)) if 0. < seconds
+            ));
+            assert!(matches!(
+                other("denoise"),
+                Some(&AttributeValue::F32(denoise)) if 0. == denoise
+            ));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Process-wide bookkeeping of the display instances that write to the
+//! same file.
+//!
+//! The renderer opens one display instance per e.g. stereo view. These
+//! all share a file name. The images of the instances are collected
+//! here as they are closed and only the last instance to close gets them
+//! all back to write them to disk together.
+use crate::ImageData;
+use std::{collections::BTreeMap, sync::Mutex};
+
+#[derive(Default)]
+struct SharedFile {
+    /// Number of display instances opened for this file so far.
+    opened: usize,
+    /// Number of display instances still open.
+    open: usize,
+    images: Vec<ImageData>,
+}
+
+static FILES: Mutex<BTreeMap<String, SharedFile>> = Mutex::new(BTreeMap::new());
+
+/// Registers a display instance writing to `file_name`.
+///
+/// Returns the index of this instance among all instances writing to
+/// this file.
+pub fn open(file_name: &str) -> usize {
+    let mut files = FILES.lock().unwrap();
+    let file = files.entry(file_name.to_string()).or_default();
+
+    file.opened += 1;
+    file.open += 1;
+
+    file.opened - 1
+}
+
+/// Returns `true` if more than one display instance writes to
+/// `file_name`.
+pub fn is_shared(file_name: &str) -> bool {
+    FILES
+        .lock()
+        .unwrap()
+        .get(file_name)
+        .is_some_and(|file| 1 < file.opened)
+}
+
+/// Hands over the image of a display instance that was closed.
+///
+/// If this was the last open instance writing to the image's file, the
+/// images of all instances are returned.
+pub fn close(image: ImageData) -> Option<Vec<ImageData>> {
+    let mut files = FILES.lock().unwrap();
+    let file_name = image.file_name.clone();
+
+    let file = match files.get_mut(&file_name) {
+        Some(file) => file,
+        None => return Some(vec![image]),
+    };
+    file.images.push(image);
+    file.open -= 1;
+
+    if 0 == file.open {
+        files.remove(&file_name).map(|file| file.images)
+    } else {
+        None
+    }
+}
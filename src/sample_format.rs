@@ -0,0 +1,259 @@
+//! Formats the renderer can send samples in and their conversion to the
+//! `f32` samples we store.
+//!
+//! Frames made only of samples smaller than `f32` can be kept `Packed`
+//! in the formats they arrived in until the whole frame is needed.
+use exr::prelude::rgba_image::f16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    F32,
+    F16,
+    U32,
+    I32,
+    U16,
+    I16,
+    U8,
+    I8,
+}
+
+impl SampleFormat {
+    /// The format for the `type_` of a `PtDspyDevFormat`. The byte order
+    /// bits are read by `ByteOrder::from_type()`.
+    pub fn from_type(type_: u32) -> Option<Self> {
+        match type_ & ndspy_sys::PkDspyMaskType {
+            ndspy_sys::PkDspyFloat32 => Some(SampleFormat::F32),
+            ndspy_sys::PkDspyFloat16 => Some(SampleFormat::F16),
+            ndspy_sys::PkDspyUnsigned32 => Some(SampleFormat::U32),
+            ndspy_sys::PkDspySigned32 => Some(SampleFormat::I32),
+            ndspy_sys::PkDspyUnsigned16 => Some(SampleFormat::U16),
+            ndspy_sys::PkDspySigned16 => Some(SampleFormat::I16),
+            ndspy_sys::PkDspyUnsigned8 => Some(SampleFormat::U8),
+            ndspy_sys::PkDspySigned8 => Some(SampleFormat::I8),
+            _ => None,
+        }
+    }
+
+    /// Size of a sample in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            SampleFormat::F32 | SampleFormat::U32 | SampleFormat::I32 => 4,
+            SampleFormat::F16 | SampleFormat::U16 | SampleFormat::I16 => 2,
+            SampleFormat::U8 | SampleFormat::I8 => 1,
+        }
+    }
+
+    /// Converts the sample at the start of `bytes`, in native byte order.
+    ///
+    /// 8 & 16 bit integers are quantized colors and mapped back to 0..1
+    /// (-1..1 if signed). 32 bit integers are usually IDs and keep
+    /// their value.
+    fn to_f32(self, bytes: &[u8]) -> f32 {
+        match self {
+            SampleFormat::F32 => f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            SampleFormat::F16 => f16::from_bits(u16::from_ne_bytes([bytes[0], bytes[1]])).to_f32(),
+            SampleFormat::U32 => u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as _,
+            SampleFormat::I32 => i32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as _,
+            SampleFormat::U16 => u16::from_ne_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32,
+            SampleFormat::I16 => i16::from_ne_bytes([bytes[0], bytes[1]]) as f32 / i16::MAX as f32,
+            SampleFormat::U8 => bytes[0] as f32 / u8::MAX as f32,
+            SampleFormat::I8 => bytes[0] as i8 as f32 / i8::MAX as f32,
+        }
+    }
+}
+
+/// The byte order the renderer sends the samples of a channel in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ByteOrder {
+    Native,
+    /// Most significant byte first.
+    HiLo,
+    /// Least significant byte first.
+    LoHi,
+}
+
+impl ByteOrder {
+    /// The byte order for the `type_` of a `PtDspyDevFormat`.
+    pub fn from_type(type_: u32) -> Self {
+        match type_ & ndspy_sys::PkDspyMaskOrder {
+            ndspy_sys::PkDspyByteOrderHiLo => ByteOrder::HiLo,
+            ndspy_sys::PkDspyByteOrderLoHi => ByteOrder::LoHi,
+            _ => ByteOrder::Native,
+        }
+    }
+
+    fn is_native(self) -> bool {
+        match self {
+            ByteOrder::Native => true,
+            ByteOrder::HiLo => cfg!(target_endian = "big"),
+            ByteOrder::LoHi => cfg!(target_endian = "little"),
+        }
+    }
+}
+
+/// The format & byte order of each channel.
+pub type ChannelFormats = [(SampleFormat, ByteOrder)];
+
+/// Number of bytes of a pixel made of samples of the given `formats`.
+pub fn pixel_size(formats: &ChannelFormats) -> usize {
+    formats.iter().map(|(format, _)| format.size()).sum()
+}
+
+/// Whether the samples of `formats` are all `f32` in native byte order.
+pub fn is_native_f32(formats: &ChannelFormats) -> bool {
+    formats
+        .iter()
+        .all(|&(format, byte_order)| SampleFormat::F32 == format && byte_order.is_native())
+}
+
+/// Copies the samples of `pixel`, of the given `formats`, to `native`
+/// in native byte order. Padding at the end of `pixel` is dropped.
+fn to_native(pixel: &[u8], formats: &ChannelFormats, native: &mut Vec<u8>) {
+    formats.iter().fold(0, |offset, &(format, byte_order)| {
+        let sample = &pixel[offset..offset + format.size()];
+        if byte_order.is_native() {
+            native.extend_from_slice(sample);
+        } else {
+            native.extend(sample.iter().rev());
+        }
+        offset + format.size()
+    });
+}
+
+/// Appends the samples of `pixel`, in native byte order, to `samples`.
+fn push_f32(pixel: &[u8], formats: &ChannelFormats, samples: &mut Vec<f32>) {
+    formats.iter().fold(0, |offset, (format, _)| {
+        samples.push(format.to_f32(&pixel[offset..]));
+        offset + format.size()
+    });
+}
+
+/// Converts the `pixels`, each `entry_size` bytes long and made of
+/// samples of the given `formats`, to `f32` samples.
+///
+/// `entry_size` must be at least the `pixel_size()` of `formats`.
+pub fn to_f32(pixels: &[u8], entry_size: usize, formats: &ChannelFormats) -> Vec<f32> {
+    let mut samples = Vec::with_capacity(formats.len() * pixels.len() / entry_size);
+    let mut native = Vec::with_capacity(pixel_size(formats));
+
+    pixels.chunks_exact(entry_size).for_each(|pixel| {
+        native.clear();
+        to_native(pixel, formats, &mut native);
+        push_f32(&native, formats, &mut samples);
+    });
+
+    samples
+}
+
+/// A frame kept in the formats its samples arrived in, in native byte
+/// order.
+#[derive(Debug)]
+pub struct Packed {
+    formats: Vec<(SampleFormat, ByteOrder)>,
+    pixel_size: usize,
+    width: usize,
+    bytes: Vec<u8>,
+}
+
+impl Packed {
+    /// A black frame, if all `formats` are smaller than `f32` so that
+    /// packing saves memory.
+    pub fn new(width: usize, height: usize, formats: &ChannelFormats) -> Option<Self> {
+        if formats.is_empty() || formats.iter().any(|(format, _)| 4 <= format.size()) {
+            return None;
+        }
+
+        let pixel_size = pixel_size(formats);
+
+        Some(Packed {
+            formats: formats.to_vec(),
+            pixel_size,
+            width,
+            bytes: vec![0; width * height * pixel_size],
+        })
+    }
+
+    /// Puts the `pixels` of a `width` × `height` bucket at `x`, `y`.
+    /// Each pixel is `entry_size` bytes long.
+    pub fn insert(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        entry_size: usize,
+        pixels: &[u8],
+    ) {
+        let mut row = Vec::with_capacity(width * self.pixel_size);
+
+        pixels
+            .chunks(width * entry_size)
+            .take(height)
+            .enumerate()
+            .for_each(|(i, pixels)| {
+                row.clear();
+                pixels
+                    .chunks_exact(entry_size)
+                    .for_each(|pixel| to_native(pixel, &self.formats, &mut row));
+
+                let start = self.pixel_size * (x + (y + i) * self.width);
+                self.bytes[start..start + row.len()].copy_from_slice(&row);
+            });
+    }
+
+    /// The whole frame as interleaved `f32` samples.
+    pub fn to_f32(&self) -> Vec<f32> {
+        let mut samples =
+            Vec::with_capacity(self.formats.len() * self.bytes.len() / self.pixel_size);
+
+        self.bytes
+            .chunks_exact(self.pixel_size)
+            .for_each(|pixel| push_f32(pixel, &self.formats, &mut samples));
+
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_order() {
+        let formats = [
+            (SampleFormat::U16, ByteOrder::HiLo),
+            (SampleFormat::U16, ByteOrder::LoHi),
+            (SampleFormat::F32, ByteOrder::HiLo),
+        ];
+        let mut pixel = vec![0x80, 0x00, 0x00, 0x80];
+        pixel.extend_from_slice(&2f32.to_be_bytes());
+        // Padding.
+        pixel.push(0xff);
+
+        assert_eq!(
+            vec![0x8000 as f32 / 65535., 0x8000 as f32 / 65535., 2.],
+            to_f32(&[pixel.clone(), pixel].concat(), 9, &formats)[3..]
+        );
+        assert!(!is_native_f32(&formats[2..]));
+        assert!(is_native_f32(&[(SampleFormat::F32, ByteOrder::Native)]));
+    }
+
+    #[test]
+    fn packed() {
+        let formats = [
+            (SampleFormat::U8, ByteOrder::Native),
+            (SampleFormat::I16, ByteOrder::HiLo),
+        ];
+        assert!(Packed::new(2, 2, &[(SampleFormat::F32, ByteOrder::Native)]).is_none());
+        assert!(Packed::new(2, 2, &[]).is_none());
+
+        let mut packed = Packed::new(3, 2, &formats).unwrap();
+        // The bottom right 2 × 1 pixels, each padded to 4 bytes.
+        packed.insert(1, 1, 2, 1, 4, &[51, 0x7f, 0xff, 0, 255, 0x80, 0x01, 0]);
+
+        assert_eq!(
+            vec![0., 0., 0., 0., 0., 0., 0., 0., 0.2, 1., 1., -1.],
+            packed.to_f32()
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! Writing an image while it renders.
+//!
+//! If no processing of the whole frame is needed (e.g. denoising) the
+//! scanline blocks of the EXR are compressed and written on a separate
+//! thread as soon as all their pixels have arrived. Only the rows that
+//! are still incomplete are kept in memory.
+use crate::{file_name, image_info, ImageData};
+use exr::{
+    block::{lines::write_all_tiles_to_buffered, BlockIndex},
+    error::{Error, UnitResult},
+    meta::{
+        attribute::{AttributeValue, ChannelInfo, SampleType},
+        header::Header,
+        Blocks,
+    },
+    prelude::rgba_image::*,
+};
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+};
+
+/// Number of samples we keep per pixel: R, G, B & A.
+const RGBA: usize = 4;
+
+#[derive(Debug)]
+pub struct Stream {
+    rows: Arc<(Mutex<Rows>, Condvar)>,
+    /// Writes to a temporary file that is renamed once complete.
+    writer: JoinHandle<UnitResult>,
+    file_name: String,
+    width: usize,
+    rgb_index: usize,
+    alpha_index: usize,
+    unpremultiply: bool,
+}
+
+#[derive(Debug, Default)]
+struct Rows {
+    /// Rows that were not written yet, by their index.
+    rows: BTreeMap<usize, Row>,
+    /// No more pixels will arrive.
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Row {
+    /// Interleaved RGBA samples.
+    samples: Vec<f32>,
+    /// Number of pixels received.
+    pixels: usize,
+}
+
+impl Stream {
+    /// Starts writing `image` to `file_name`.
+    ///
+    /// The image must have RGBA channels.
+    pub fn start(image: &ImageData, file_name: String) -> Self {
+        let rows = Arc::new((Mutex::new(Rows::default()), Condvar::new()));
+
+        let mut image_info = image_info(image);
+        // Neither the render time nor whether the render completes are
+        // known yet. The header is patched when the image is finished.
+        image_info
+            .layer_attributes
+            .other
+            .insert(Text::from("renderTime").unwrap(), AttributeValue::F32(0.));
+        image_info.layer_attributes.other.insert(
+            Text::from("renderIncomplete").unwrap(),
+            AttributeValue::I32(1),
+        );
+
+        let header = Header::new(
+            Text::from("RGBA").unwrap(),
+            image_info.resolution,
+            SmallVec::from_vec(vec![
+                ChannelInfo::new(Text::from("A").unwrap(), SampleType::F32, true),
+                ChannelInfo::new(Text::from("B").unwrap(), SampleType::F32, false),
+                ChannelInfo::new(Text::from("G").unwrap(), SampleType::F32, false),
+                ChannelInfo::new(Text::from("R").unwrap(), SampleType::F32, false),
+            ]),
+        )
+        .with_shared_attributes(image_info.image_attributes)
+        .with_attributes(image_info.layer_attributes)
+        .with_encoding(
+            image_info.encoding.compression,
+            Blocks::ScanLines,
+            image_info.encoding.line_order,
+        );
+
+        let writer = {
+            let rows = rows.clone();
+            let width = image.width;
+            let temporary_file_name = file_name::temporary(&file_name);
+
+            std::thread::spawn(move || {
+                write_all_tiles_to_buffered(
+                    BufWriter::new(File::create(temporary_file_name)?),
+                    std::iter::once(header).collect(),
+                    |_, block_index| block(&rows, width, block_index),
+                    // Blocks are compressed one after the other as they
+                    // become complete anyway.
+                    write_options::low(),
+                )
+            })
+        };
+
+        Stream {
+            rows,
+            writer,
+            file_name,
+            width: image.width,
+            rgb_index: image.rgb_index.unwrap(),
+            alpha_index: image.alpha_index.unwrap(),
+            unpremultiply: !image.premultiply,
+        }
+    }
+
+    /// Adds the pixels of a bucket. `data` holds the interleaved
+    /// `num_channels` channels of `width` × `height` pixels.
+    pub fn push(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        num_channels: usize,
+        data: &[f32],
+    ) {
+        let (rows, complete) = &*self.rows;
+        let mut rows = rows.lock().unwrap();
+
+        data.chunks(width * num_channels)
+            .take(height)
+            .enumerate()
+            .for_each(|(i, pixels)| {
+                let row = rows.rows.entry(y + i).or_insert_with(|| Row {
+                    samples: vec![0.0; RGBA * self.width],
+                    pixels: 0,
+                });
+
+                pixels
+                    .chunks(num_channels)
+                    .zip(row.samples[RGBA * x..].chunks_mut(RGBA))
+                    .for_each(|(pixel, rgba)| {
+                        let alpha = pixel[self.alpha_index];
+                        // Ignore pixels whose alpha is zero.
+                        let scale = if self.unpremultiply && 0.0 != alpha {
+                            1. / alpha
+                        } else {
+                            1.
+                        };
+                        rgba[0] = pixel[self.rgb_index] * scale;
+                        rgba[1] = pixel[self.rgb_index + 1] * scale;
+                        rgba[2] = pixel[self.rgb_index + 2] * scale;
+                        rgba[3] = alpha;
+                    });
+                row.pixels += width;
+            });
+
+        complete.notify_all();
+    }
+
+    /// Waits for the image to be written. Pixels that never arrived are
+    /// written black and the image stays marked incomplete.
+    ///
+    /// Returns `true` if the image was written.
+    pub fn finish(self, image: &ImageData) -> bool {
+        let file_name = self.file_name.clone();
+        let result = self.close();
+
+        crate::write_to_file(&file_name, |temporary_file_name| {
+            result?;
+
+            if let Some(render_time) = image.render_time {
+                patch_attribute(
+                    temporary_file_name,
+                    "renderTime",
+                    &render_time.as_secs_f32().to_le_bytes(),
+                )?;
+            }
+            if !image.is_incomplete() {
+                patch_attribute(temporary_file_name, "renderIncomplete", &0i32.to_le_bytes())?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Stops writing and removes what was written. Whatever is at the
+    /// file name of the image is left alone.
+    pub fn discard(self) {
+        let temporary_file_name = file_name::temporary(&self.file_name);
+
+        self.close().ok();
+        std::fs::remove_file(&temporary_file_name).ok();
+    }
+
+    /// Lets the writer write the remaining blocks and waits for it.
+    fn close(self) -> UnitResult {
+        {
+            let (rows, complete) = &*self.rows;
+            rows.lock().unwrap().closed = true;
+            complete.notify_all();
+        }
+
+        self.writer.join().unwrap_or(Err(Error::Aborted))
+    }
+}
+
+/// Overwrites the value of the attribute `name` in the header of the
+/// single part EXR `file_name`. The new `value` must be as long as the
+/// one written.
+fn patch_attribute(file_name: &str, name: &str, value: &[u8]) -> UnitResult {
+    let mut file = OpenOptions::new().read(true).write(true).open(file_name)?;
+
+    let offset = {
+        let mut header = BufReader::new(&file);
+        // Magic number & version.
+        header.seek_relative(8)?;
+        let mut offset = 8;
+
+        let mut attribute_name = Vec::new();
+        let mut attribute_type = Vec::new();
+        loop {
+            attribute_name.clear();
+            attribute_type.clear();
+            offset += header.read_until(0, &mut attribute_name)?;
+            // The header ends with an empty name.
+            if attribute_name.len() <= 1 {
+                return Err(Error::Invalid("attribute to patch is missing".into()));
+            }
+            offset += header.read_until(0, &mut attribute_type)?;
+
+            let mut size = [0; 4];
+            header.read_exact(&mut size)?;
+            offset += 4;
+            let size = i32::from_le_bytes(size) as usize;
+
+            if name.as_bytes() == &attribute_name[..attribute_name.len() - 1] {
+                if size != value.len() {
+                    return Err(Error::Invalid("attribute to patch has another size".into()));
+                }
+                break offset;
+            }
+
+            header.seek_relative(size as _)?;
+            offset += size;
+        }
+    };
+
+    file.seek(SeekFrom::Start(offset as _))?;
+    file.write_all(value)?;
+
+    Ok(())
+}
+
+/// Waits for the rows of a block and returns them laid out as the EXR
+/// wants them: each line holds all samples of one channel after
+/// another, in alphabetical order of the channels.
+fn block(rows: &(Mutex<Rows>, Condvar), width: usize, block_index: BlockIndex) -> Vec<u8> {
+    let (rows, complete) = rows;
+    let mut bytes = Vec::with_capacity(block_index.pixel_size.area() * RGBA * 4);
+
+    let y_start = block_index.pixel_position.y();
+    for y in y_start..y_start + block_index.pixel_size.height() {
+        let row = {
+            let mut rows = complete
+                .wait_while(rows.lock().unwrap(), |rows| {
+                    !rows.closed && rows.rows.get(&y).map_or(true, |row| row.pixels < width)
+                })
+                .unwrap();
+
+            rows.rows.remove(&y)
+        };
+
+        // A, B, G, R.
+        for channel in (0..RGBA).rev() {
+            for x in 0..width {
+                let sample = row
+                    .as_ref()
+                    .map_or(0.0, |row| row.samples[RGBA * x + channel]);
+                bytes.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::*;
+    use exr::{
+        meta::attribute::{AttributeValue, Text},
+        prelude::simple_image,
+    };
+
+    fn render_incomplete(image: &simple_image::Image) -> Option<&AttributeValue> {
+        image.layers[0]
+            .attributes
+            .other
+            .get(&Text::from("renderIncomplete").unwrap())
+    }
+
+    #[test]
+    fn incomplete_streamed_image_is_marked() {
+        for on_abort in ["mark", "raw"] {
+            let file_name = file_name("incomplete.exr");
+            let display = Display::open(
+                &file_name,
+                8,
+                8,
+                &RGBA,
+                &[("on_abort", Value::String(&[on_abort]))],
+            )
+            .unwrap();
+            display.send(&rgba(8, 4, |_, _| [1., 1., 1., 1.]), 4);
+            display.close();
+
+            let image = read_exr(&file_name);
+            assert_eq!(Some(&AttributeValue::I32(1)), render_incomplete(&image));
+            // Rows that never arrived are black.
+            assert_eq!(1., channel(&image, "R")[0]);
+            assert_eq!(0., channel(&image, "R")[63]);
+        }
+    }
+
+    #[test]
+    fn complete_streamed_image_is_unmarked() {
+        let file_name = file_name("complete.exr");
+        render(&file_name, 8, 8, &[], &rgba(8, 8, |_, _| [1.; 4]));
+
+        let image = read_exr(&file_name);
+        assert_eq!(Some(&AttributeValue::I32(0)), render_incomplete(&image));
+        assert_eq!(vec![1.; 64], channel(&image, "R"));
+    }
+
+    #[test]
+    fn discard_keeps_existing_file() {
+        let file_name = file_name("discard.exr");
+        std::fs::write(&file_name, "previous render").unwrap();
+
+        let display = Display::open(
+            &file_name,
+            8,
+            8,
+            &RGBA,
+            &[
+                ("on_abort", Value::String(&["discard"])),
+                ("overwrite", Value::String(&["always"])),
+            ],
+        )
+        .unwrap();
+        display.send(&rgba(8, 4, |_, _| [1., 1., 1., 1.]), 4);
+        display.close();
+
+        assert_eq!(
+            "previous render",
+            std::fs::read_to_string(&file_name).unwrap()
+        );
+        assert!(!std::path::Path::new(&crate::file_name::temporary(&file_name)).exists());
+    }
+}
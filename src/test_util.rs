@@ -0,0 +1,282 @@
+//! Drives the display through its C entry points, like a renderer does,
+//! and reads back what it wrote.
+use crate::{DspyImageClose, DspyImageData, DspyImageOpen, DspyImageQuery};
+use exr::prelude::simple_image;
+use std::{
+    ffi::CString,
+    mem,
+    os::raw::{c_char, c_int, c_void},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Red, green, blue & alpha as 32bit float channels.
+pub const RGBA: [(&str, u32); 4] = [
+    ("r", ndspy_sys::PkDspyFloat32),
+    ("g", ndspy_sys::PkDspyFloat32),
+    ("b", ndspy_sys::PkDspyFloat32),
+    ("a", ndspy_sys::PkDspyFloat32),
+];
+
+/// The value of a user parameter.
+pub enum Value<'a> {
+    Int(&'a [i32]),
+    Float(&'a [f32]),
+    String(&'a [&'a str]),
+}
+
+/// A display instance opened through `DspyImageOpen()`.
+pub struct Display {
+    pub handle: ndspy_sys::PtDspyImageHandle,
+    width: usize,
+    num_channels: usize,
+}
+
+/// A file name no other test uses, in a temporary directory.
+pub fn file_name(name: &str) -> String {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let directory = std::env::temp_dir().join(format!("r-display-test-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+
+    directory
+        .join(format!(
+            "{}_{}",
+            COUNT.fetch_add(1, Ordering::Relaxed),
+            name
+        ))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Interleaved RGBA samples of `width` × `height` pixels.
+pub fn rgba(width: usize, height: usize, pixel: impl Fn(usize, usize) -> [f32; 4]) -> Vec<f32> {
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .flat_map(|(x, y)| pixel(x, y).to_vec())
+        .collect()
+}
+
+impl Display {
+    /// Opens `file_name` with `channels` (name & `PkDspy*` type).
+    ///
+    /// Denoising is off unless `parameters` ask for it.
+    pub fn open(
+        file_name: &str,
+        width: usize,
+        height: usize,
+        channels: &[(&str, u32)],
+        parameters: &[(&str, Value)],
+    ) -> Result<Self, ndspy_sys::PtDspyError> {
+        let no_denoise = ("denoise", Value::Float(&[0.]));
+        let parameters = parameters.iter().chain(std::iter::once(&no_denoise));
+
+        // Keeps the strings alive until the display is open.
+        let mut c_strings = Vec::new();
+        let mut c_string_pointers = Vec::<Vec<*const c_char>>::new();
+
+        let mut user_parameters = parameters
+            .map(|(name, value)| {
+                let name = CString::new(*name).unwrap();
+                let (value_type, value_count, value, nbytes) = match value {
+                    Value::Int(v) => (b'i', v.len(), v.as_ptr() as *const c_void, 4 * v.len()),
+                    Value::Float(v) => (b'f', v.len(), v.as_ptr() as *const c_void, 4 * v.len()),
+                    Value::String(v) => {
+                        let strings = v
+                            .iter()
+                            .map(|s| CString::new(*s).unwrap())
+                            .collect::<Vec<_>>();
+                        c_string_pointers.push(strings.iter().map(|s| s.as_ptr()).collect());
+                        c_strings.push(strings);
+                        (
+                            b's',
+                            v.len(),
+                            c_string_pointers.last().unwrap().as_ptr() as *const c_void,
+                            mem::size_of::<*const c_char>() * v.len(),
+                        )
+                    }
+                };
+                let user_parameter = ndspy_sys::UserParameter {
+                    name: name.as_ptr(),
+                    valueType: value_type as _,
+                    valueCount: value_count as _,
+                    value,
+                    nbytes: nbytes as _,
+                };
+                c_strings.push(vec![name]);
+                user_parameter
+            })
+            .collect::<Vec<_>>();
+
+        let channel_names = channels
+            .iter()
+            .map(|(name, _)| CString::new(*name).unwrap())
+            .collect::<Vec<_>>();
+        let mut formats = channels
+            .iter()
+            .zip(channel_names.iter())
+            .map(|((_, type_), name)| ndspy_sys::PtDspyDevFormat {
+                name: name.as_ptr(),
+                type_: *type_,
+            })
+            .collect::<Vec<_>>();
+
+        let driver_name = CString::new("r-display").unwrap();
+        let output_filename = CString::new(file_name).unwrap();
+        let mut handle: ndspy_sys::PtDspyImageHandle = std::ptr::null_mut();
+
+        match DspyImageOpen(
+            &mut handle,
+            driver_name.as_ptr(),
+            output_filename.as_ptr(),
+            width as _,
+            height as _,
+            user_parameters.len() as _,
+            user_parameters.as_mut_ptr(),
+            formats.len() as _,
+            formats.as_mut_ptr(),
+            std::ptr::null_mut(),
+        ) {
+            ndspy_sys::PtDspyError_PkDspyErrorNone => Ok(Display {
+                handle,
+                width,
+                num_channels: channels.len(),
+            }),
+            error => Err(error),
+        }
+    }
+
+    /// Sends the bucket `x`..`x + width` × `y`..`y + height` of 32bit
+    /// float samples.
+    pub fn data(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        samples: &[f32],
+    ) -> ndspy_sys::PtDspyError {
+        assert_eq!(self.num_channels * width * height, samples.len());
+
+        self.data_bounds(x as _, (x + width) as _, y as _, (y + height) as _, samples)
+    }
+
+    /// Sends the bucket `x`..`x + width` × `y`..`y + height` of pixels
+    /// `entry_size` bytes long.
+    pub fn data_bytes(
+        &self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        entry_size: usize,
+        bytes: &[u8],
+    ) -> ndspy_sys::PtDspyError {
+        DspyImageData(
+            self.handle,
+            x as _,
+            (x + width) as _,
+            y as _,
+            (y + height) as _,
+            entry_size as _,
+            bytes.as_ptr(),
+        )
+    }
+
+    /// Sends 32bit float `samples` for the bucket `x_min`..`x_max_plus_one`
+    /// × `y_min`..`y_max_plus_one`, which need not be inside the frame.
+    pub fn data_bounds(
+        &self,
+        x_min: i32,
+        x_max_plus_one: i32,
+        y_min: i32,
+        y_max_plus_one: i32,
+        samples: &[f32],
+    ) -> ndspy_sys::PtDspyError {
+        DspyImageData(
+            self.handle,
+            x_min,
+            x_max_plus_one,
+            y_min,
+            y_max_plus_one,
+            (self.num_channels * mem::size_of::<f32>()) as _,
+            samples.as_ptr() as *const u8,
+        )
+    }
+
+    /// Sends all of `samples`, a whole frame, in buckets of
+    /// `bucket_size` × `bucket_size` pixels.
+    pub fn send(&self, samples: &[f32], bucket_size: usize) {
+        let height = samples.len() / (self.num_channels * self.width);
+
+        for y in (0..height).step_by(bucket_size) {
+            for x in (0..self.width).step_by(bucket_size) {
+                let width = bucket_size.min(self.width - x);
+                let bucket_height = bucket_size.min(height - y);
+                let bucket = (y..y + bucket_height)
+                    .flat_map(|y| {
+                        let start = self.num_channels * (x + y * self.width);
+                        samples[start..start + self.num_channels * width]
+                            .iter()
+                            .copied()
+                    })
+                    .collect::<Vec<_>>();
+
+                assert_eq!(
+                    ndspy_sys::PtDspyError_PkDspyErrorNone,
+                    self.data(x, y, width, bucket_height, &bucket)
+                );
+            }
+        }
+    }
+
+    /// Answers `query_type` in `answer`.
+    pub fn query<T>(
+        &self,
+        query_type: ndspy_sys::PtDspyQueryType,
+        answer: &mut T,
+    ) -> ndspy_sys::PtDspyError {
+        DspyImageQuery(
+            self.handle,
+            query_type,
+            mem::size_of::<T>() as c_int,
+            answer as *mut T as *mut c_void,
+        )
+    }
+
+    pub fn close(self) -> ndspy_sys::PtDspyError {
+        DspyImageClose(self.handle)
+    }
+}
+
+/// Renders `samples`, a whole RGBA frame, to `file_name` in one bucket.
+pub fn render(
+    file_name: &str,
+    width: usize,
+    height: usize,
+    parameters: &[(&str, Value)],
+    samples: &[f32],
+) {
+    let display = Display::open(file_name, width, height, &RGBA, parameters).unwrap();
+    display.send(samples, width.max(height));
+    assert_eq!(ndspy_sys::PtDspyError_PkDspyErrorNone, display.close());
+}
+
+pub fn read_exr(file_name: &str) -> simple_image::Image {
+    simple_image::Image::read_from_file(file_name, simple_image::read_options::high()).unwrap()
+}
+
+/// The samples of the channel called `name` in the first layer of
+/// `image`.
+pub fn channel(image: &simple_image::Image, name: &str) -> Vec<f32> {
+    let channel = image.layers[0]
+        .channels
+        .iter()
+        .find(|channel| channel.name.eq(name))
+        .unwrap_or_else(|| panic!("no channel {}", name));
+
+    match &channel.samples {
+        simple_image::Samples::F32(samples) => samples.clone(),
+        simple_image::Samples::F16(samples) => samples.iter().map(|s| s.to_f32()).collect(),
+        simple_image::Samples::U32(samples) => samples.iter().map(|&s| s as f32).collect(),
+    }
+}
@@ -0,0 +1,383 @@
+//! Live preview in the [tev](https://github.com/Tom94/tev) image viewer
+//! through its TCP IPC protocol.
+use crate::view_transform::ViewTransform;
+use std::{
+    io::Write,
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// The address tev listens on by default.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:14158";
+
+/// Packet types of the tev IPC protocol.
+const CREATE_IMAGE: u8 = 4;
+const UPDATE_IMAGE_V3: u8 = 6;
+
+/// Number of rows sent per packet when sending a whole image.
+const ROWS_PER_PACKET: usize = 128;
+
+/// How long connecting or sending may block the renderer before tev is
+/// given up on.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct Tev {
+    stream: TcpStream,
+    image_name: String,
+    channel_names: Vec<String>,
+    /// Applied to the RGB channels starting at the given index.
+    view_transform: Option<(ViewTransform, usize)>,
+}
+
+impl Tev {
+    /// Connects to tev at `address` and creates an image named
+    /// `image_name` with the given channels.
+    ///
+    /// tev encodes to sRGB itself so the RGB channels are sent as the
+    /// linear values `view_transform` shows on an sRGB display.
+    pub fn connect(
+        address: &str,
+        image_name: &str,
+        width: usize,
+        height: usize,
+        channel_names: &[String],
+        view_transform: Option<(ViewTransform, usize)>,
+    ) -> Option<Self> {
+        let stream = connect(address)
+            .map_err(|e| eprintln!("[r-display] could not connect to tev at {}: {}", address, e))
+            .ok()?;
+        stream.set_nodelay(true).ok();
+        stream.set_write_timeout(Some(TIMEOUT)).ok()?;
+
+        let mut tev = Tev {
+            stream,
+            image_name: image_name.to_string(),
+            // tev shows `R`, `G`, `B` & `A` as the color channels.
+            channel_names: channel_names
+                .iter()
+                .map(|name| {
+                    if 1 == name.len() {
+                        name.to_ascii_uppercase()
+                    } else {
+                        name.clone()
+                    }
+                })
+                .collect(),
+            view_transform,
+        };
+
+        let mut packet = Packet::new(CREATE_IMAGE);
+        packet.bool(true);
+        packet.string(&tev.image_name);
+        packet.i32(width as _);
+        packet.i32(height as _);
+        packet.i32(tev.channel_names.len() as _);
+        tev.channel_names
+            .iter()
+            .for_each(|name| packet.string(name));
+
+        if tev.send(packet) {
+            Some(tev)
+        } else {
+            None
+        }
+    }
+
+    /// Sends the pixels of a bucket. `data` holds the interleaved
+    /// channels of `width` × `height` pixels.
+    pub fn update(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        data: &[f32],
+    ) -> bool {
+        let num_channels = self.channel_names.len();
+
+        let mut packet = Packet::new(UPDATE_IMAGE_V3);
+        packet.bool(false);
+        packet.string(&self.image_name);
+        packet.i32(num_channels as _);
+        self.channel_names
+            .iter()
+            .for_each(|name| packet.string(name));
+        packet.i32(x as _);
+        packet.i32(y as _);
+        packet.i32(width as _);
+        packet.i32(height as _);
+        // Channel offsets and strides, in samples.
+        (0..num_channels).for_each(|offset| packet.i64(offset as _));
+        (0..num_channels).for_each(|_| packet.i64(num_channels as _));
+        data[..width * height * num_channels]
+            .chunks(num_channels)
+            .for_each(|pixel| match self.view_transform {
+                Some((view_transform, rgb_index)) => {
+                    let rgb = view_transform.apply_display_linear([
+                        pixel[rgb_index],
+                        pixel[rgb_index + 1],
+                        pixel[rgb_index + 2],
+                    ]);
+                    pixel.iter().enumerate().for_each(|(i, &sample)| {
+                        packet.f32(if (rgb_index..rgb_index + 3).contains(&i) {
+                            rgb[i - rgb_index]
+                        } else {
+                            sample
+                        })
+                    });
+                }
+                None => pixel.iter().for_each(|&sample| packet.f32(sample)),
+            });
+
+        self.send(packet)
+    }
+
+    /// Sends all pixels of an image of the given `width`.
+    pub fn update_image(&mut self, width: usize, data: &[f32]) -> bool {
+        let row_len = width * self.channel_names.len();
+
+        data.chunks(ROWS_PER_PACKET * row_len)
+            .enumerate()
+            .all(|(i, rows)| self.update(0, i * ROWS_PER_PACKET, width, rows.len() / row_len, rows))
+    }
+
+    fn send(&mut self, packet: Packet) -> bool {
+        self.stream
+            .write_all(&packet.finish())
+            .map_err(|e| eprintln!("[r-display] lost connection to tev: {}", e))
+            .is_ok()
+    }
+}
+
+/// Connects to the first address `address` resolves to that answers
+/// within the timeout.
+fn connect(address: &str) -> std::io::Result<TcpStream> {
+    let mut error = None;
+
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = Some(e),
+        }
+    }
+
+    Err(error
+        .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address")))
+}
+
+/// A little endian IPC packet, prefixed with its length.
+struct Packet(Vec<u8>);
+
+impl Packet {
+    fn new(packet_type: u8) -> Self {
+        // The length is filled in by `finish()`.
+        Packet(vec![0, 0, 0, 0, packet_type])
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.0.push(value as _);
+    }
+
+    fn string(&mut self, value: &str) {
+        self.0.extend_from_slice(value.as_bytes());
+        self.0.push(0);
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = (self.0.len() as u32).to_le_bytes();
+        self.0[..4].copy_from_slice(&len);
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+    use std::{convert::TryInto, io::Read, net::TcpListener};
+
+    /// Reads the fields of a packet.
+    struct Reader<'a>(&'a [u8]);
+
+    impl Reader<'_> {
+        fn take(&mut self, len: usize) -> &[u8] {
+            let (taken, rest) = self.0.split_at(len);
+            self.0 = rest;
+            taken
+        }
+
+        fn u8(&mut self) -> u8 {
+            self.take(1)[0]
+        }
+
+        fn string(&mut self) -> String {
+            let len = self.0.iter().position(|&b| 0 == b).expect("unterminated");
+            let string = String::from_utf8(self.take(len).to_vec()).unwrap();
+            self.take(1);
+            string
+        }
+
+        fn i32(&mut self) -> i32 {
+            i32::from_le_bytes(self.take(4).try_into().unwrap())
+        }
+
+        fn i64(&mut self) -> i64 {
+            i64::from_le_bytes(self.take(8).try_into().unwrap())
+        }
+
+        fn f32(&mut self) -> f32 {
+            f32::from_le_bytes(self.take(4).try_into().unwrap())
+        }
+    }
+
+    /// Splits what tev received into packets, checking their length
+    /// prefixes.
+    fn packets(mut received: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+
+        while !received.is_empty() {
+            let len = u32::from_le_bytes(received[..4].try_into().unwrap()) as usize;
+            assert!(5 <= len && len <= received.len());
+            packets.push(received[4..len].to_vec());
+            received = &received[len..];
+        }
+
+        packets
+    }
+
+    /// Checks an update packet and returns its position, size &
+    /// samples.
+    fn update(packet: &[u8], image_name: &str) -> (i32, i32, i32, i32, Vec<f32>) {
+        let mut packet = Reader(packet);
+
+        assert_eq!(UPDATE_IMAGE_V3, packet.u8());
+        // Don't grab focus.
+        assert_eq!(0, packet.u8());
+        assert_eq!(image_name, packet.string());
+        assert_eq!(4, packet.i32());
+        for name in ["R", "G", "B", "A"] {
+            assert_eq!(name, packet.string());
+        }
+        let (x, y, width, height) = (packet.i32(), packet.i32(), packet.i32(), packet.i32());
+        assert_eq!(
+            vec![0, 1, 2, 3],
+            (0..4).map(|_| packet.i64()).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![4; 4], (0..4).map(|_| packet.i64()).collect::<Vec<_>>());
+        let samples = (0..4 * width * height).map(|_| packet.f32()).collect();
+        assert!(packet.0.is_empty());
+
+        (x, y, width, height, samples)
+    }
+
+    #[test]
+    fn protocol() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let tev = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            listener
+                .accept()
+                .unwrap()
+                .0
+                .read_to_end(&mut received)
+                .unwrap();
+            received
+        });
+
+        let file_name = file_name("tev.exr");
+        let samples = rgba(4, 2, |x, y| [x as f32, y as f32, 0.5, 1.]);
+        let display = Display::open(
+            &file_name,
+            4,
+            2,
+            &RGBA,
+            &[
+                ("tev", Value::String(&[&address])),
+                ("view_transform", Value::String(&["linear"])),
+                // Keeps the image until it is closed.
+                ("tile_size", Value::Int(&[16, 16])),
+            ],
+        )
+        .unwrap();
+        display.send(&samples, 2);
+        assert_eq!(ndspy_sys::PtDspyError_PkDspyErrorNone, display.close());
+
+        let received = tev.join().unwrap();
+        let packets = packets(&received);
+        assert_eq!(4, packets.len());
+
+        let mut create = Reader(&packets[0]);
+        assert_eq!(CREATE_IMAGE, create.u8());
+        // Grab focus.
+        assert_eq!(1, create.u8());
+        assert_eq!(file_name, create.string());
+        assert_eq!((4, 2, 4), (create.i32(), create.i32(), create.i32()));
+        for name in ["R", "G", "B", "A"] {
+            assert_eq!(name, create.string());
+        }
+        assert!(create.0.is_empty());
+
+        // A packet per bucket.
+        let bucket = |x: usize| {
+            (0..2)
+                .flat_map(|y| samples[4 * (x + 4 * y)..4 * (x + 2 + 4 * y)].to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!((0, 0, 2, 2, bucket(0)), update(&packets[1], &file_name));
+        assert_eq!((2, 0, 2, 2, bucket(2)), update(&packets[2], &file_name));
+
+        // The whole image once it is closed.
+        assert_eq!((0, 0, 4, 2, samples), update(&packets[3], &file_name));
+    }
+
+    #[test]
+    fn unresponsive_tev_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // Accepts but never reads, until the test is done or gives up.
+        let (done, wait) = std::sync::mpsc::channel::<()>();
+        let tev = std::thread::spawn(move || {
+            let _connection = listener.accept().unwrap();
+            wait.recv_timeout(60 * TIMEOUT).ok();
+        });
+
+        let file_name = file_name("unresponsive_tev.exr");
+        let (width, height) = (1024, 1024);
+        let display = Display::open(
+            &file_name,
+            width,
+            height,
+            &RGBA,
+            &[("tev", Value::String(&[&address]))],
+        )
+        .unwrap();
+
+        // Far more than the socket buffers hold, a few MB each.
+        let samples = rgba(width, height, |_, _| [1.; 4]);
+        let start = std::time::Instant::now();
+        display.send(&samples, 64);
+        assert!(start.elapsed() < 20 * TIMEOUT);
+        let image = unsafe { &*(display.handle as *const crate::ImageData) };
+        assert!(image.tev.is_none());
+
+        assert_eq!(ndspy_sys::PtDspyError_PkDspyErrorNone, display.close());
+        assert!(std::path::Path::new(&file_name).exists());
+
+        done.send(()).unwrap();
+        tev.join().unwrap();
+    }
+}
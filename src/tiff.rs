@@ -0,0 +1,115 @@
+//! Writes deflate compressed 8 & 16 bit RGB(A) TIFFs.
+use ::tiff::{
+    encoder::{colortype, compression::Deflate, TiffEncoder},
+    tags::Tag,
+};
+use std::{fs::File, io::BufWriter};
+
+/// Writes `samples`, interleaved `num_channels` (3 or 4) channels of
+/// `width` × `height` pixels, with `bit_depth` (8 or 16) bits each.
+///
+/// A fourth channel is written as unassociated alpha.
+pub fn write(
+    file_name: &str,
+    width: usize,
+    height: usize,
+    num_channels: usize,
+    bit_depth: u8,
+    samples: &[u16],
+) -> std::io::Result<()> {
+    let mut tiff = TiffEncoder::new(BufWriter::new(File::create(file_name)?)).map_err(error)?;
+    let (width, height) = (width as u32, height as u32);
+
+    match (num_channels, bit_depth) {
+        (4, 16) => write_image::<colortype::RGBA16, _>(&mut tiff, width, height, samples),
+        (4, _) => write_image::<colortype::RGBA8, _>(&mut tiff, width, height, &bytes(samples)),
+        (_, 16) => write_image::<colortype::RGB16, _>(&mut tiff, width, height, samples),
+        _ => write_image::<colortype::RGB8, _>(&mut tiff, width, height, &bytes(samples)),
+    }
+    .map_err(error)
+}
+
+fn write_image<C, W>(
+    tiff: &mut TiffEncoder<W>,
+    width: u32,
+    height: u32,
+    data: &[C::Inner],
+) -> ::tiff::TiffResult<()>
+where
+    C: colortype::ColorType,
+    [C::Inner]: ::tiff::encoder::TiffValue,
+    W: std::io::Write + std::io::Seek,
+{
+    let mut image = tiff.new_image_with_compression::<C, _>(width, height, Deflate::default())?;
+    if 4 == C::BITS_PER_SAMPLE.len() {
+        // Unassociated alpha.
+        image.encoder().write_tag(Tag::ExtraSamples, 2u16)?;
+    }
+    image.write_data(data)
+}
+
+fn bytes(samples: &[u16]) -> Vec<u8> {
+    samples.iter().map(|&sample| sample as u8).collect()
+}
+
+fn error(error: ::tiff::TiffError) -> std::io::Error {
+    std::io::Error::other(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::file_name;
+    use ::tiff::{
+        decoder::{Decoder, DecodingResult},
+        tags::Tag,
+        ColorType,
+    };
+
+    /// Reads a TIFF.
+    ///
+    /// Returns width, height, number of channels, bit depth & samples.
+    fn read(file_name: &str) -> (usize, usize, usize, u8, Vec<u16>) {
+        let mut tiff = Decoder::new(File::open(file_name).unwrap()).unwrap();
+        let (width, height) = tiff.dimensions().unwrap();
+
+        // Deflate compressed.
+        assert_eq!(8, tiff.get_tag_u32(Tag::Compression).unwrap());
+
+        let (num_channels, bit_depth) = match tiff.colortype().unwrap() {
+            ColorType::RGB(bit_depth) => (3, bit_depth),
+            ColorType::RGBA(bit_depth) => {
+                assert_eq!(2, tiff.get_tag_u32(Tag::ExtraSamples).unwrap());
+                (4, bit_depth)
+            }
+            color_type => panic!("unexpected color type {:?}", color_type),
+        };
+        let samples = match tiff.read_image().unwrap() {
+            DecodingResult::U8(samples) => samples.into_iter().map(u16::from).collect(),
+            DecodingResult::U16(samples) => samples,
+            _ => panic!("unexpected sample type"),
+        };
+
+        (width as _, height as _, num_channels, bit_depth, samples)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (width, height) = (7, 5);
+
+        for (num_channels, bit_depth) in [(3, 8), (4, 8), (3, 16), (4, 16)] {
+            let max = if 8 == bit_depth { 255 } else { 65535 };
+            let samples = (0..width * height * num_channels)
+                .map(|i| (i * 7919 % (max + 1)) as u16)
+                .collect::<Vec<_>>();
+
+            let file_name = file_name("round_trip.tif");
+            write(&file_name, width, height, num_channels, bit_depth, &samples).unwrap();
+
+            assert_eq!(
+                (width, height, num_channels, bit_depth, samples),
+                read(&file_name)
+            );
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! Viewing transforms turning linear scene values into display values
+//! for 8 & 16 bit outputs and viewers.
+//!
+//! All curves assume the image has Rec.709/sRGB primaries.
+use crate::ImageData;
+use exr::{math::Vec2, meta::attribute::Preview};
+
+/// Longest side of the preview stored in an EXR, in pixels.
+const PREVIEW_SIZE: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    /// Exposure only; values are neither clipped nor encoded.
+    Linear,
+    /// Clipped and encoded with the sRGB OETF.
+    Srgb,
+    /// Clipped and encoded with the Rec.709 OETF.
+    Rec709,
+    /// Clipped and encoded with a pure power function.
+    Gamma,
+    /// John Hable's filmic curve, then sRGB.
+    Filmic,
+    /// Stephen Hill's fit of the ACES RRT & sRGB ODT.
+    Aces,
+}
+
+impl Curve {
+    /// Parses the value of the `view_transform` parameter.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "linear" | "raw" => Some(Curve::Linear),
+            "srgb" => Some(Curve::Srgb),
+            "rec709" | "rec.709" => Some(Curve::Rec709),
+            "gamma" => Some(Curve::Gamma),
+            "filmic" => Some(Curve::Filmic),
+            "aces" => Some(Curve::Aces),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ViewTransform {
+    /// In stops.
+    pub exposure: f32,
+    pub curve: Curve,
+    /// Used by [`Curve::Gamma`].
+    pub gamma: f32,
+}
+
+impl ViewTransform {
+    /// Returns display encoded values, in 0..1 unless the curve is
+    /// [`Curve::Linear`].
+    pub fn apply(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let scale = self.exposure.exp2();
+        let rgb = rgb.map(|value| value * scale);
+
+        match self.curve {
+            Curve::Linear => rgb,
+            Curve::Srgb => rgb.map(|value| srgb_oetf(clip(value))),
+            Curve::Rec709 => rgb.map(|value| rec709_oetf(clip(value))),
+            Curve::Gamma => rgb.map(|value| clip(value).powf(1. / self.gamma)),
+            Curve::Filmic => {
+                let white = hable(11.2);
+                rgb.map(|value| srgb_oetf(clip(hable(value.max(0.)) / white)))
+            }
+            Curve::Aces => {
+                let rgb = multiply(&ACES_INPUT, rgb).map(|value| {
+                    (value * (value + 0.0245786) - 0.000090537)
+                        / (value * (0.983729 * value + 0.432951) + 0.238081)
+                });
+                multiply(&ACES_OUTPUT, rgb).map(|value| srgb_oetf(clip(value)))
+            }
+        }
+    }
+
+    /// Returns what [`apply`](Self::apply) shows on an sRGB display as
+    /// linear values, for viewers that encode to sRGB themselves.
+    pub fn apply_display_linear(&self, rgb: [f32; 3]) -> [f32; 3] {
+        match self.curve {
+            Curve::Linear => self.apply(rgb),
+            _ => self.apply(rgb).map(srgb_eotf),
+        }
+    }
+}
+
+/// A small 8 bit version of the RGBA channels of `image` for the EXR
+/// `preview` attribute.
+pub fn preview(image: &ImageData) -> Option<Preview> {
+    let (rgb_index, alpha_index) = (image.rgb_index?, image.alpha_index?);
+
+    let scale = image.width.max(image.height).div_ceil(PREVIEW_SIZE);
+    let size = Vec2(image.width.div_ceil(scale), image.height.div_ceil(scale));
+
+    let mut pixel_data = Vec::with_capacity(4 * size.area());
+    for y in 0..size.height() {
+        for x in 0..size.width() {
+            // Average the pixels the preview pixel covers.
+            let mut rgba = [0f32; 4];
+            let mut count = 0;
+            for source_y in y * scale..((y + 1) * scale).min(image.height) {
+                for source_x in x * scale..((x + 1) * scale).min(image.width) {
+                    let pixel =
+                        &image.data[image.num_channels * (source_x + source_y * image.width)..];
+                    rgba[0] += pixel[rgb_index];
+                    rgba[1] += pixel[rgb_index + 1];
+                    rgba[2] += pixel[rgb_index + 2];
+                    rgba[3] += pixel[alpha_index];
+                    count += 1;
+                }
+            }
+            let rgba = rgba.map(|value| value / count as f32);
+
+            let rgb = image.view_transform.apply([rgba[0], rgba[1], rgba[2]]);
+            pixel_data.extend(
+                [rgb[0], rgb[1], rgb[2], rgba[3]]
+                    .iter()
+                    .map(|&value| (clip(value) * 255. + 0.5) as u8 as i8),
+            );
+        }
+    }
+
+    Some(Preview { size, pixel_data })
+}
+
+/// Clips to 0..1, mapping NaN to zero.
+fn clip(value: f32) -> f32 {
+    if value.is_nan() {
+        0.
+    } else {
+        value.clamp(0., 1.)
+    }
+}
+
+fn srgb_oetf(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        12.92 * value
+    } else {
+        1.055 * value.powf(1. / 2.4) - 0.055
+    }
+}
+
+fn srgb_eotf(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn rec709_oetf(value: f32) -> f32 {
+    if value < 0.018 {
+        4.5 * value
+    } else {
+        1.099 * value.powf(0.45) - 0.099
+    }
+}
+
+/// John Hable's filmic curve from Uncharted 2.
+fn hable(value: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+
+    (value * (A * value + C * B) + D * E) / (value * (A * value + B) + D * F) - E / F
+}
+
+/// sRGB to ACES AP1, including the RRT's saturation adjustment.
+const ACES_INPUT: [[f32; 3]; 3] = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777],
+];
+
+/// ODT saturation adjustment and AP1 back to sRGB.
+const ACES_OUTPUT: [[f32; 3]; 3] = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602],
+];
+
+fn multiply(matrix: &[[f32; 3]; 3], rgb: [f32; 3]) -> [f32; 3] {
+    matrix.map(|row| row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    fn view_transform(curve: Curve) -> ViewTransform {
+        ViewTransform {
+            exposure: 0.,
+            curve,
+            gamma: 2.2,
+        }
+    }
+
+    #[test]
+    fn curves() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-3;
+
+        assert!(close(
+            0.4614,
+            view_transform(Curve::Srgb).apply([0.18; 3])[0]
+        ));
+        assert!(close(
+            0.4090,
+            view_transform(Curve::Rec709).apply([0.18; 3])[0]
+        ));
+        assert!(close(
+            0.18f32.powf(1. / 2.2),
+            view_transform(Curve::Gamma).apply([0.18; 3])[0]
+        ));
+        assert_eq!([2., -1., 40.], {
+            let mut linear = view_transform(Curve::Linear);
+            linear.exposure = 1.;
+            linear.apply([1., -0.5, 20.])
+        });
+
+        for curve in [
+            Curve::Srgb,
+            Curve::Rec709,
+            Curve::Gamma,
+            Curve::Filmic,
+            Curve::Aces,
+        ] {
+            let view_transform = view_transform(curve);
+            // Clipped, NaN free & monotonic.
+            assert!(view_transform
+                .apply([-1., f32::NAN, 0.])
+                .iter()
+                .all(|&value| (0. ..1e-6).contains(&value)));
+            let values = [0.01, 0.18, 1., 4., 16., 1000.]
+                .iter()
+                .map(|&value| view_transform.apply([value; 3])[1])
+                .collect::<Vec<_>>();
+            assert!(values.windows(2).all(|pair| pair[0] <= pair[1]));
+            assert!(values.iter().all(|value| (0. ..=1.).contains(value)));
+
+            // What a viewer encoding to sRGB itself shows is the same.
+            let displayed = view_transform.apply_display_linear([0.3, 0.6, 2.]);
+            let expected = view_transform.apply([0.3, 0.6, 2.]);
+            displayed
+                .iter()
+                .zip(expected.iter())
+                .for_each(|(&displayed, &expected)| assert!(close(expected, srgb_oetf(displayed))));
+        }
+        // The filmic white point.
+        assert!(close(1., view_transform(Curve::Filmic).apply([11.2; 3])[0]));
+    }
+
+    #[test]
+    fn exr_preview() {
+        let file_name = file_name("preview.exr");
+        render(
+            &file_name,
+            300,
+            10,
+            &[("preview", Value::Int(&[1]))],
+            &rgba(300, 10, |_, _| [0.18, 0.18, 0.18, 1.]),
+        );
+
+        let image = read_exr(&file_name);
+        let preview = image.layers[0].attributes.preview.as_ref().unwrap();
+        // The longest side is at most 128 pixels.
+        assert_eq!(Vec2(100, 4), preview.size);
+        preview.pixel_data.chunks(4).for_each(|pixel| {
+            assert_eq!(
+                vec![118, 118, 118, 255],
+                pixel.iter().map(|&v| v as u8).collect::<Vec<_>>()
+            )
+        });
+    }
+}